@@ -0,0 +1,61 @@
+-- Columns and tables used by Kapir on top of Kern's create.sql
+-- Run after the schema has been created: mysql -u kabina -p kabina < sql/kapir.sql
+
+-- group bookings, one order can take more seats
+ALTER TABLE taxi_order ADD COLUMN passengers INT NOT NULL DEFAULT 1;
+ALTER TABLE freetaxi_order ADD COLUMN passengers INT NOT NULL DEFAULT 1;
+
+-- cabs deactivated by an admin cannot report themselves FREE
+ALTER TABLE cab ADD COLUMN active BOOLEAN NOT NULL DEFAULT true;
+
+-- retired stops are kept for the history of orders but are not loaded
+ALTER TABLE stop ADD COLUMN active BOOLEAN NOT NULL DEFAULT true;
+
+-- why a customer has cancelled, see POST /orders/{id}/cancel
+ALTER TABLE taxi_order ADD COLUMN cancel_reason VARCHAR(255) NULL;
+
+-- percentiles of pickup and completion times, updated by Kapir on GET /stats
+INSERT INTO stat (name, int_val) VALUES ('OrderPickupTimeP50', 0), ('OrderPickupTimeP90', 0),
+  ('OrderPickupTimeP99', 0), ('OrderCompleteTimeP50', 0), ('OrderCompleteTimeP90', 0),
+  ('OrderCompleteTimeP99', 0);
+
+-- wait for pickup counted from the requested time and detour in percent of the direct ride
+INSERT INTO stat (name, int_val) VALUES ('AvgOrderWaitTime', 0), ('AvgOrderDetour', 0);
+
+-- timestamps are UTC since Kapir stopped writing local time; rows written before that can be converted with
+-- UPDATE taxi_order SET received=CONVERT_TZ(received, 'SYSTEM', '+00:00'), started=CONVERT_TZ(started, 'SYSTEM', '+00:00'),
+--   completed=CONVERT_TZ(completed, 'SYSTEM', '+00:00'), at_time=CONVERT_TZ(at_time, 'SYSTEM', '+00:00');
+-- UPDATE leg SET started=CONVERT_TZ(started, 'SYSTEM', '+00:00'), completed=CONVERT_TZ(completed, 'SYSTEM', '+00:00');
+
+-- wait and detour against what the customer accepted, share of shared trips
+INSERT INTO stat (name, int_val) VALUES ('AvgOrderWaitOfMax', 0), ('AvgOrderDetourOfLoss', 0), ('OrderPooledShare', 0);
+
+-- fares in cents, estimated when ordered and counted on completion
+ALTER TABLE taxi_order ADD COLUMN est_fare INT NULL;
+ALTER TABLE taxi_order ADD COLUMN fare INT NULL;
+
+-- driver shifts and breaks, a row without 'ended' is still open
+CREATE TABLE shift (
+  id BIGINT AUTO_INCREMENT PRIMARY KEY,
+  cab_id BIGINT NOT NULL,
+  started DATETIME NOT NULL,
+  ended DATETIME NULL,
+  INDEX shift_cab (cab_id, started)
+);
+CREATE TABLE shift_break (
+  id BIGINT AUTO_INCREMENT PRIMARY KEY,
+  shift_id BIGINT NOT NULL,
+  started DATETIME NOT NULL,
+  ended DATETIME NULL,
+  INDEX break_shift (shift_id)
+);
+
+-- vehicle features and what orders require, bit masks: 1=wheelchair, 2=luggage, 4=child seat, 8=electric;
+-- a cab can serve an order if (cab.features & order.requires) = order.requires
+ALTER TABLE cab ADD COLUMN features INT NOT NULL DEFAULT 0;
+ALTER TABLE taxi_order ADD COLUMN requires INT NOT NULL DEFAULT 0;
+ALTER TABLE freetaxi_order ADD COLUMN requires INT NOT NULL DEFAULT 0;
+
+-- new legs and routes written by Kapir take their ids from the database, explicit ids still work
+ALTER TABLE route MODIFY id BIGINT NOT NULL AUTO_INCREMENT;
+ALTER TABLE leg MODIFY id BIGINT NOT NULL AUTO_INCREMENT;
@@ -0,0 +1,68 @@
+use crate::distance::get_dist;
+use crate::metrics::get_conn;
+use crate::model::{Cab, Order};
+use crate::service::{insert_route, select_free_cabs, select_orders_to_dispatch};
+use log::{debug, info, warn};
+use mysql::{Pool, PooledConn};
+use std::thread;
+use std::time::Duration;
+
+// Kern is the solver; this greedy fallback lets small deployments and demos run without it.
+// Enabled with 'dispatcher = true' in kapir.toml, orders get 'dispatcher_delay' seconds for Kern first
+pub static mut DISPATCHER_DELAY: i64 = 60;
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn start_dispatcher(pool: Pool) {
+    info!("Dispatcher started, delay={}s", unsafe { DISPATCHER_DELAY });
+    thread::spawn(move || loop {
+        thread::sleep(DISPATCH_INTERVAL);
+        match get_conn(&pool) {
+            Ok(mut c) => {
+                let count = dispatch(&mut c);
+                if count > 0 {
+                    info!("Dispatcher assigned orders: {}", count);
+                }
+            }
+            Err(err) => warn!("Dispatcher cannot connect to the DB: {}", err),
+        }
+    });
+}
+
+fn dispatch(c: &mut PooledConn) -> usize {
+    let orders = select_orders_to_dispatch(c, unsafe { DISPATCHER_DELAY });
+    if orders.is_empty() {
+        return 0;
+    }
+    let mut cabs = select_free_cabs(c);
+    let mut count = 0;
+    for o in orders {
+        match nearest_cab(&cabs, &o) {
+            Some(idx) => {
+                let cab = cabs.swap_remove(idx);
+                if insert_route(c, &cab, &o) {
+                    debug!("Dispatcher, order_id={} cab_id={}", o.id, cab.id);
+                    count += 1;
+                }
+            }
+            None => debug!("Dispatcher, no cab for order_id={}", o.id),
+        }
+        if cabs.is_empty() {
+            break;
+        }
+    }
+    return count;
+}
+
+// the nearest cab with enough seats and the features needed that gets to the customer within max_wait
+fn nearest_cab(cabs: &[Cab], o: &Order) -> Option<usize> {
+    return cabs
+        .iter()
+        .enumerate()
+        .filter(|(_, cab)| {
+            cab.seats as i32 >= o.passengers
+                && cab.features.covers(&o.requires)
+                && get_dist(cab.location, o.from) as i32 <= o.wait
+        })
+        .min_by_key(|(_, cab)| get_dist(cab.location, o.from))
+        .map(|(idx, _)| idx);
+}
@@ -1,10 +1,12 @@
 use crate::model::Stop;
+use std::sync::RwLock;
 
 pub const MAXSTOPSNUMB: usize = 5200;
 pub const CAB_SPEED: i16 = 30; // km/h
 
-pub static mut STOPS: Vec<Stop> = vec![];
-pub static mut DIST: [[i16; MAXSTOPSNUMB]; MAXSTOPSNUMB] = [[0; MAXSTOPSNUMB]; MAXSTOPSNUMB];
+// both are replaced together by reload_distance while requests are being served
+pub static STOPS: RwLock<Vec<Stop>> = RwLock::new(vec![]);
+pub static DIST: RwLock<Vec<[i16; MAXSTOPSNUMB]>> = RwLock::new(vec![]);
 const M_PI: f64 = 3.14159265358979323846264338327950288;
 const M_PI_180: f64 = M_PI / 180.0;
 const REV_M_PI_180: f64 = 180.0 / M_PI;
@@ -28,24 +30,62 @@ fn dist(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
     return dist;
 }
 
-pub fn init_distance() {
-    unsafe {
-        for i in 0..STOPS.len() {
-            DIST[i][i] = 0;
-            for j in i + 1..STOPS.len() {
-                let mut d = dist(
-                    STOPS[i].latitude,
-                    STOPS[i].longitude,
-                    STOPS[j].latitude,
-                    STOPS[j].longitude,
-                ) * (60.0 / CAB_SPEED as f64);
-                if d as i16 == 0 {
-                    d = 1.0;
-                } // a transfer takes at least one minute.
-                DIST[STOPS[i].id as usize][STOPS[j].id as usize] = d as i16; // TASK: we might need a better precision - meters/seconds
-                DIST[STOPS[j].id as usize][STOPS[i].id as usize] =
-                    DIST[STOPS[i].id as usize][STOPS[j].id as usize];
-            }
+// The new matrix is computed aside, readers see either the old or the new network, never a mix.
+// Retired stops stay in the matrix, orders and legs that use them still have their distances;
+// STOPS, which new orders are checked against, gets only the active ones.
+pub fn reload_distance(stops: Vec<Stop>, retired: &[Stop]) {
+    let all: Vec<Stop> = stops.iter().chain(retired).cloned().collect();
+    let matrix = count_distance(&all);
+    let mut dist_guard = DIST.write().unwrap();
+    let mut stops_guard = STOPS.write().unwrap();
+    *dist_guard = matrix;
+    *stops_guard = stops;
+}
+
+fn count_distance(stops: &[Stop]) -> Vec<[i16; MAXSTOPSNUMB]> {
+    let mut matrix: Vec<[i16; MAXSTOPSNUMB]> = vec![[0; MAXSTOPSNUMB]; MAXSTOPSNUMB];
+    for i in 0..stops.len() {
+        matrix[stops[i].id as usize][stops[i].id as usize] = 0;
+        for j in i + 1..stops.len() {
+            let mut d = dist(
+                stops[i].latitude,
+                stops[i].longitude,
+                stops[j].latitude,
+                stops[j].longitude,
+            ) * (60.0 / CAB_SPEED as f64);
+            if d as i16 == 0 {
+                d = 1.0;
+            } // a transfer takes at least one minute.
+            matrix[stops[i].id as usize][stops[j].id as usize] = d as i16; // TASK: we might need a better precision - meters/seconds
+            matrix[stops[j].id as usize][stops[i].id as usize] =
+                matrix[stops[i].id as usize][stops[j].id as usize];
         }
     }
+    return matrix;
+}
+
+// minutes between two stops, 0 if the matrix has not been initialized yet
+pub fn get_dist(from: i32, to: i32) -> i16 {
+    let dist = DIST.read().unwrap();
+    if dist.is_empty()
+        || from < 0
+        || to < 0
+        || from as usize >= MAXSTOPSNUMB
+        || to as usize >= MAXSTOPSNUMB
+    {
+        return 0;
+    }
+    return dist[from as usize][to as usize];
+}
+
+pub fn stops_count() -> usize {
+    return STOPS.read().unwrap().len();
+}
+
+pub fn is_distance_ready() -> bool {
+    return DIST.read().unwrap().len() == MAXSTOPSNUMB;
+}
+
+pub fn get_stop(id: i64) -> Option<Stop> {
+    return STOPS.read().unwrap().iter().find(|&x| x.id == id).cloned();
 }
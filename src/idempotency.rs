@@ -0,0 +1,166 @@
+use actix_web::body::{to_bytes, MessageBody};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use log::{debug, info};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+pub const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+// how long (in seconds) the first response is kept for retries, can be overwritten in kapir.toml
+pub static mut IDEMPOTENCY_WINDOW: u64 = 600;
+
+// expired entries are dropped at most this often, not on every request
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+// the response is None while the first request is in progress
+struct Entry {
+    at: Instant,
+    request: u64,
+    response: Option<(StatusCode, Bytes)>,
+}
+
+impl Entry {
+    fn expired(&self, now: Instant) -> bool {
+        return now.duration_since(self.at) >= window();
+    }
+}
+
+// set on a response that must not be replayed, e.g. v1 answers a DB error with 200
+pub struct NoReplay;
+
+struct Responses {
+    entries: HashMap<String, Entry>,
+    pruned: Instant,
+}
+
+static RESPONSES: LazyLock<Mutex<Responses>> = LazyLock::new(|| {
+    Mutex::new(Responses {
+        entries: HashMap::new(),
+        pruned: Instant::now(),
+    })
+});
+
+fn window() -> Duration {
+    return Duration::from_secs(unsafe { IDEMPOTENCY_WINDOW });
+}
+
+// The key is InProgress while the handler runs; if it fails, panics or the request is dropped,
+// the entry goes away with the guard and a retry is executed instead of getting 409 for the window
+struct Pending {
+    key: Option<String>,
+}
+
+impl Pending {
+    fn done(mut self, request: u64, status: StatusCode, body: Bytes) {
+        if let Some(key) = self.key.take() {
+            debug!("Idempotent response stored, key={}", key);
+            RESPONSES.lock().unwrap().entries.insert(
+                key,
+                Entry {
+                    at: Instant::now(),
+                    request,
+                    response: Some((status, body)),
+                },
+            );
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if let Ok(mut responses) = RESPONSES.lock() {
+                responses.entries.remove(&key);
+            }
+        }
+    }
+}
+
+// the request as the handler got it, its Debug form is enough to tell two bodies apart
+fn fingerprint<B: Debug>(body: &B) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    format!("{:?}", body).hash(&mut hasher);
+    return hasher.finish();
+}
+
+// Mobile networks retry POSTs; a request with a key seen before gets the first response again
+// instead of being executed twice. Keys are scoped by user and path. Only a successful response
+// is kept, after a failure the retry is executed; the same key with another body gets 422.
+pub async fn idempotent<B, F>(
+    req: &HttpRequest,
+    user: &str,
+    body: &B,
+    f: F,
+) -> Result<HttpResponse, Error>
+where
+    B: Debug,
+    F: FnOnce() -> Result<HttpResponse, Error>,
+{
+    let key = match req.headers().get(IDEMPOTENCY_HEADER) {
+        Some(k) => match k.to_str() {
+            Ok(k) => format!("{} {} {}", user, req.path(), k),
+            Err(_) => return Ok(HttpResponse::BadRequest().json("Wrong Idempotency-Key")),
+        },
+        None => return f(),
+    };
+    let request = fingerprint(body);
+    let pending = {
+        let mut responses = RESPONSES.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(responses.pruned) >= PRUNE_EVERY {
+            responses.entries.retain(|_, e| !e.expired(now));
+            responses.pruned = now;
+        }
+        match responses.entries.get(&key) {
+            Some(e) if e.expired(now) => {}
+            Some(e) if e.request != request => {
+                info!("Idempotency-Key reused with another request, key={}", key);
+                return Ok(HttpResponse::UnprocessableEntity()
+                    .json("Idempotency-Key used before with another request"));
+            }
+            Some(Entry {
+                response: Some((status, body)),
+                ..
+            }) => {
+                info!("Idempotent replay, key={}", key);
+                return Ok(HttpResponse::build(*status)
+                    .content_type("application/json")
+                    .insert_header(("Idempotent-Replayed", "true"))
+                    .body(body.clone()));
+            }
+            Some(Entry { response: None, .. }) => {
+                info!("Idempotent request still in progress, key={}", key);
+                return Ok(HttpResponse::Conflict().json("Request with this key is in progress"));
+            }
+            None => {}
+        }
+        responses.entries.insert(
+            key.clone(),
+            Entry {
+                at: now,
+                request,
+                response: None,
+            },
+        );
+        Pending { key: Some(key) }
+    };
+    let resp = f()?;
+    let status = resp.status();
+    if !status.is_success() || resp.extensions().get::<NoReplay>().is_some() {
+        // 'pending' drops the key
+        return Ok(resp);
+    }
+    let body = match to_bytes(resp.into_body()).await {
+        Ok(b) => b,
+        Err(_) => return Ok(HttpResponse::InternalServerError().finish()),
+    };
+    pending.done(request, status, body.clone());
+    return Ok(HttpResponse::build(status)
+        .content_type("application/json")
+        .body(body.boxed()));
+}
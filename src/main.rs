@@ -1,8 +1,9 @@
 use actix_cors::Cors;
-use actix_web::{get, post, put, web, App, Error, HttpResponse, HttpServer, Result}; // Responder
+use actix_web::middleware::{from_fn, DefaultHeaders, NormalizePath};
+use actix_web::{get, post, put, web, App, Error, HttpRequest, HttpResponse, HttpServer, Result}; // Responder
 use actix_web_httpauth::extractors::basic::BasicAuth;
 use derive_more::{Display, From};
-use log::{info, LevelFilter};
+use log::{debug, error, info, LevelFilter};
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target},
@@ -18,16 +19,45 @@ use std::collections::HashMap;
 use std::env;
 mod service;
 use service::{
-    assign_free_cab, assign_to_route, init_read_stops, insert_order, select_cab, select_order,
-    select_orders, select_route_by_cab, select_route_by_id, select_stats, select_traffik,
-    update_cab, update_leg, update_order, update_route,
+    assign_free_cab, assign_to_route, cancel_order, deactivate_cab, end_break, end_shift,
+    init_read_stops, insert_and_match_order, insert_cab, insert_stop, ping_db, reload_stops,
+    retire_stop, select_cab, select_cab_history, select_cab_report, select_cabs,
+    select_customer_history, select_customer_report, select_leaderboard, select_order,
+    select_orders, select_quote, select_route_by_cab, select_route_by_id, select_shifts,
+    select_stats, select_stats_cabs, select_stats_orders, select_traffik, select_upcoming_orders,
+    start_break, start_shift, update_cab, update_cab_info, update_leg, update_order, update_route,
+    update_stop, BOOKING_HORIZON, MAX_DRIVING, POOL_MATCHING, SHIFTS_REQUIRED,
 };
 mod model;
-use model::{Cab, CabAssign, Leg, Order, Route};
+use model::{
+    Cab, CabAssign, CabInfo, CabQuery, CabUpdate, Health, HistoryQuery, LeaderboardQuery, Leg,
+    Notification, Order, OrderCancel, OrderHistory, OrdersQuery, Quote, QuoteRequest, Readiness,
+    Report, Route, RouteWithOrders, Shift, ShiftQuery, ShiftReport, Stats, StatsQuery, Stop,
+    StopTraffic,
+};
+mod dispatcher;
 mod distance;
+mod idempotency;
+mod metrics;
+use metrics::{conn_returned, conn_taken, encode_metrics, get_conn, track_requests};
+mod notify;
 use crate::{distance::STOPS, service::select_route_with_orders};
-use distance::init_distance;
+use dispatcher::{start_dispatcher, DISPATCHER_DELAY};
+use distance::{get_stop, is_distance_ready, stops_count};
+use idempotency::{idempotent, NoReplay, IDEMPOTENCY_WINDOW};
+use notify::take_notifications;
+#[macro_use]
+mod openapi;
+mod pricing;
+use pricing::read_tariff;
 mod stats;
+mod timestamp;
+mod version;
+use stats::window_slots;
+use timestamp::set_display_zone;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+use version::{api_v2, is_v2, respond, Outcome};
 
 #[derive(Display, From, Debug)]
 pub enum MyError {
@@ -59,6 +89,44 @@ async fn main() -> std::io::Result<()> {
     dbname = cfg["dbname"].clone();
     bind_host = cfg["myhost"].clone();
     bind_port = cfg["myport"].clone().parse::<u16>().unwrap();
+    if let Some(window) = cfg.get("idempotency_window") {
+        unsafe {
+            IDEMPOTENCY_WINDOW = window.parse::<u64>().unwrap();
+        }
+    }
+    if let Some(zone) = cfg.get("display_zone") {
+        set_display_zone(zone).unwrap();
+    }
+    let dispatcher: bool = match cfg.get("dispatcher") {
+        Some(d) => d.parse::<bool>().unwrap(),
+        None => false,
+    };
+    if let Some(delay) = cfg.get("dispatcher_delay") {
+        unsafe {
+            DISPATCHER_DELAY = delay.parse::<i64>().unwrap();
+        }
+    }
+    if let Some(matching) = cfg.get("pool_matching") {
+        unsafe {
+            POOL_MATCHING = matching.parse::<bool>().unwrap();
+        }
+    }
+    read_tariff(&cfg);
+    if let Some(required) = cfg.get("shifts_required") {
+        unsafe {
+            SHIFTS_REQUIRED = required.parse::<bool>().unwrap();
+        }
+    }
+    if let Some(driving) = cfg.get("max_driving") {
+        unsafe {
+            MAX_DRIVING = driving.parse::<i64>().unwrap();
+        }
+    }
+    if let Some(horizon) = cfg.get("booking_horizon") {
+        unsafe {
+            BOOKING_HORIZON = horizon.parse::<i64>().unwrap();
+        }
+    }
 
     // possible to overwrite config file
     let args: Vec<String> = env::args().collect();
@@ -78,52 +146,174 @@ async fn main() -> std::io::Result<()> {
         .db_name(Some(dbname));
     let pool = Pool::new(opts).unwrap();
 
-    init_dist_service(&pool).await;
+    init_dist_service(&pool).await?;
+    if dispatcher {
+        start_dispatcher(pool.clone());
+    }
 
     HttpServer::new(move || {
         let cors = Cors::permissive();
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .wrap(cors)
-            .service(put_cab) // curl -H "Content-type: application/json" -u cab2:cab2 -X PUT -d '{ "Id":2, "Location":123, "Status":"FREE"}' http://localhost:8080/cabs
-            .service(put_cab2) // {"Id":0,"Location":0,"Status":"FREE","Name":""}
-            .service(get_cab) // curl -u cab1:cab1 http://localhost:8080/cabs/1916
-            .service(get_order) // curl -u cab2:cab2 http://localhost:8080/orders/51150
-            .service(get_order2) // curl -u cab2:cab2 http://localhost:8080/orders
-            .service(get_order3) // curl -u cust1:cust1 http://localhost:8080/orders/
-            .service(put_order) // curl -H "Content-type: application/json" -X PUT -u cust1:cust1 -d '{ "Id":775791, "Status":"ASSIGNED", "From":0,"To":0,"Wait":0,"Loss":0}' http://localhost:8080/orders
-            .service(put_order2)
-            .service(post_order) //curl -H "Content-type: application/json" -H "Accept: application/json"  -X POST -u "cust28:cust28" -d '{"From":4001, "To":4002, "Wait":10, "Loss":90, "Shared": true}' http://localhost:8080/orders
-            .service(post_order2)
-            .service(put_leg) // curl -H "Content-type: application/json" -H "Accept: application/json"  -X PUT -u cab1:cab1 -d '{ "Id":17081, "Status":"STARTED"}' http://localhost:8080/legs
-            .service(put_leg2)
-            .service(put_route) // curl -H "Content-type: application/json" -H "Accept: application/json"  -X PUT -u cab1:cab1 -d '{ "Id":9724, "Status":"ASSIGNED"}' http://localhost:8080/routes
-            .service(put_route2)
-            .service(get_route) // curl -u cab2:cab2 http://localhost:8080/routes
-            .service(get_route2)
-            .service(get_route_by_id)
-            .service(get_route_with_orders) //http://localhost:8080/routewithorders
-            .service(get_route_with_orders2)
-            .service(get_stops) // curl -u cab2:cab2 http://localhost:8080/stops
-            .service(get_stops2)
-            .service(get_traffic) // 
-            .service(get_stats)
-            .service(post_assign_free_cab) // curl -H "Content-type: application/json" -H "Accept: application/json"  -X POST -u cab1:cab1 -d '{ "CustId":100, "From":0, "To":0,"Shared":true,"Loss":10}' http://localhost:8080/assignfreecab
-            .service(post_assign_free_cab2)
-            .service(post_assign_to_route) // curl -H "Content-type: application/json" -H "Accept: application/json"  -X POST -u cab1:cab1 -d '{ "CustId":100, "From":0, "To":0,"Shared":true,"Loss":10}' http://localhost:8080/assigntoroute
-            .service(post_assign_to_route2)
+            .wrap(from_fn(track_requests))
+            .service(get_metrics) // curl http://localhost:8080/metrics
+            .service(get_health) // curl http://localhost:8080/health
+            .service(get_ready) // curl http://localhost:8080/ready
+            .service(SwaggerUi::new("/swagger-ui/{_:.*}").url("/openapi.json", ApiDoc::openapi())) // http://localhost:8080/swagger-ui/
+            .service(
+                web::scope("/v1")
+                    .wrap(NormalizePath::trim())
+                    .configure(config_services),
+            )
+            // the same services without -1 for missing values, see version.rs
+            .service(
+                web::scope("/v2")
+                    .wrap(from_fn(api_v2))
+                    .wrap(NormalizePath::trim())
+                    .configure(config_services),
+            )
+            // paths without version are kept for Kab and Kaut apps
+            .service(
+                web::scope("")
+                    .wrap(
+                        DefaultHeaders::new()
+                            .add(("Deprecation", "true"))
+                            .add(("Link", "</v1>; rel=\"successor-version\"")),
+                    )
+                    .wrap(NormalizePath::trim())
+                    .configure(config_services),
+            )
     })
     .bind((bind_host, bind_port))?
     .run()
     .await
 }
 
-async fn init_dist_service(pool: &Pool) {
-    init_read_stops(pool.get_conn().unwrap()).await;
-    init_distance();
+services! {
+    put_cab, // curl -H "Content-type: application/json" -u cab2:cab2 -X PUT -d '{ "Id":2, "Location":123, "Status":"FREE"}' http://localhost:8080/v1/cabs
+    get_cab_notifications, // curl -u cab1:cab1 http://localhost:8080/v1/cabs/notifications
+    get_cab_history, // curl -u cab1:cab1 "http://localhost:8080/v1/cabs/history?page=2&size=10"
+    post_start_shift, // curl -u cab1:cab1 -X POST http://localhost:8080/v1/cabs/shift/start
+    post_end_shift, // curl -u cab1:cab1 -X POST http://localhost:8080/v1/cabs/shift/end
+    post_start_break, // curl -u cab1:cab1 -X POST http://localhost:8080/v1/cabs/shift/break/start
+    post_end_break, // curl -u cab1:cab1 -X POST http://localhost:8080/v1/cabs/shift/break/end
+    get_cab_shifts, // curl -u cab1:cab1 "http://localhost:8080/v1/cabs/1/shifts?from=2025-05-01T00:00:00"
+    get_cabs, // curl -u adm1:adm1 "http://localhost:8080/v1/cabs?status=FREE&location=123"
+    post_cab, // curl -H "Content-type: application/json" -u adm1:adm1 -X POST -d '{ "Id":2, "Location":123, "Status":"CHARGING", "Seats":8, "Name":"ABC-123"}' http://localhost:8080/v1/cabs
+    put_cab_info, // curl -H "Content-type: application/json" -u adm1:adm1 -X PUT -d '{ "Seats":4, "Name":"ABC-123"}' http://localhost:8080/v1/cabs/2
+    post_deactivate_cab, // curl -u adm1:adm1 -X POST http://localhost:8080/v1/cabs/2/deactivate
+    get_cab, // curl -u cab1:cab1 http://localhost:8080/v1/cabs/1916
+    get_upcoming_orders, // curl -u cust1:cust1 http://localhost:8080/v1/orders/upcoming
+    post_cancel_order, // curl -H "Content-type: application/json" -u cust1:cust1 -X POST -d '{"Reason":"changed plans"}' http://localhost:8080/v1/orders/51150/cancel
+    get_order_history, // curl -u cust1:cust1 "http://localhost:8080/v1/orders/history?from=2025-05-01T00:00:00&status=COMPLETED"
+    get_order, // curl -u cab2:cab2 http://localhost:8080/v1/orders/51150
+    get_orders, // curl -u cust1:cust1 http://localhost:8080/v1/orders
+    put_order, // curl -H "Content-type: application/json" -X PUT -u cust1:cust1 -d '{ "Id":775791, "Status":"ASSIGNED", "From":0,"To":0,"Wait":0,"Loss":0}' http://localhost:8080/v1/orders
+    post_quote, // curl -H "Content-type: application/json" -X POST -u cust1:cust1 -d '{"From":4001, "To":4002, "Shared":true, "Passengers":2}' http://localhost:8080/v1/quotes
+    post_order, //curl -H "Content-type: application/json" -H "Accept: application/json"  -X POST -u "cust28:cust28" -d '{"From":4001, "To":4002, "Wait":10, "Loss":90, "Shared": true}' http://localhost:8080/v1/orders
+    put_leg, // curl -H "Content-type: application/json" -H "Accept: application/json"  -X PUT -u cab1:cab1 -d '{ "Id":17081, "Status":"STARTED"}' http://localhost:8080/v1/legs
+    put_route, // curl -H "Content-type: application/json" -H "Accept: application/json"  -X PUT -u cab1:cab1 -d '{ "Id":9724, "Status":"ASSIGNED"}' http://localhost:8080/v1/routes
+    get_route, // curl -u cab2:cab2 http://localhost:8080/v1/routes
+    get_route_by_id,
+    get_route_with_orders, //http://localhost:8080/v1/routewithorders
+    get_stops, // curl -u cab2:cab2 http://localhost:8080/v1/stops
+    post_stop, // curl -H "Content-type: application/json" -u adm1:adm1 -X POST -d '{"id":5199,"bearing":0,"latitude":47.49,"longitude":19.04,"name":"New stop"}' http://localhost:8080/v1/stops
+    put_stop,
+    post_retire_stop, // curl -u adm1:adm1 -X POST http://localhost:8080/v1/stops/5199/retire
+    post_reload_stops, // curl -u adm1:adm1 -X POST http://localhost:8080/v1/stops/reload
+    get_traffic, // curl -u cab2:cab2 http://localhost:8080/v1/stops/10/traffic
+    get_stats, // curl -u adm1:adm1 "http://localhost:8080/v1/stats?window=15m"
+    get_leaderboard, // curl -u adm1:adm1 "http://localhost:8080/v1/stats/cabs/leaderboard?by=idle&order=desc&limit=10"
+    get_cab_report, // curl -u cab1:cab1 http://localhost:8080/v1/stats/cabs/1
+    get_customer_report, // curl -u cust1:cust1 http://localhost:8080/v1/stats/customers/1
+    post_assign_free_cab, // curl -H "Content-type: application/json" -H "Accept: application/json"  -X POST -u cab1:cab1 -d '{ "CustId":100, "From":0, "To":0,"Shared":true,"Loss":10}' http://localhost:8080/v1/assignfreecab
+    post_assign_to_route, // curl -H "Content-type: application/json" -H "Accept: application/json"  -X POST -u cab1:cab1 -d '{ "CustId":100, "From":0, "To":0,"Shared":true,"Loss":10}' http://localhost:8080/v1/assigntoroute
+}
+
+async fn init_dist_service(pool: &Pool) -> std::io::Result<()> {
+    let conn = match pool.get_conn() {
+        Ok(c) => c,
+        Err(err) => {
+            error!("Cannot connect to the DB: {}", err);
+            return Err(std::io::Error::other(err.to_string()));
+        }
+    };
+    match init_read_stops(conn).await {
+        Ok(count) => info!("Stops read: {}", count),
+        Err(err) => {
+            error!("Cannot start: {}", err);
+            return Err(std::io::Error::other(err));
+        }
+    }
+    return Ok(());
+}
+
+// CONTROLLERS
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "The process is up", body = Health),
+    )
+)]
+#[get("/health")]
+async fn get_health() -> Result<HttpResponse, Error> {
+    return Ok(HttpResponse::Ok().json(Health {
+        status: "UP".to_string(),
+    }));
 }
 
-// CONTROLLERS, most duplicated to respond to a slash at the end too
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Ready to serve requests", body = Readiness),
+        (status = 503, description = "Not ready, see which check failed", body = Readiness),
+    )
+)]
+#[get("/ready")]
+async fn get_ready(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    let db: bool = match get_conn(&db_pool) {
+        Ok(mut c) => ping_db(&mut c),
+        Err(_) => false,
+    };
+    let stops: usize = stops_count();
+    let distance: bool = is_distance_ready();
+    let ready = db && stops > 0 && distance;
+    let readiness = Readiness {
+        ready,
+        db,
+        stops,
+        distance,
+    };
+    if ready {
+        return Ok(HttpResponse::Ok().json(readiness));
+    }
+    return Ok(HttpResponse::ServiceUnavailable().json(readiness));
+}
+
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Metrics in Prometheus text format", content_type = "text/plain", body = String),
+    )
+)]
+#[get("/metrics")]
+async fn get_metrics(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    let (orders, cabs) = match get_conn(&db_pool) {
+        Ok(mut c) => (select_stats_orders(&mut c), select_stats_cabs(&mut c)),
+        Err(_) => (vec![], vec![]),
+    };
+    return Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(encode_metrics(orders, cabs)));
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "Location and status of a cab", body = Cab),
+    )
+)]
 #[get("/cabs/{id}")]
 async fn get_cab(
     id: web::Path<i64>,
@@ -136,40 +326,310 @@ async fn get_cab(
     return get_object(get_auth_id(auth.user_id()), myid, db_pool, select_cab);
 }
 
-#[put("/cabs")]
-async fn put_cab(
-    obj: web::Json<Cab>,
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "Messages for the cab since the last call, e.g. cancelled orders", body = [Notification]),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/cabs/notifications")]
+async fn get_cab_notifications(auth: BasicAuth) -> Result<HttpResponse, Error> {
+    if !auth.user_id().starts_with("cab") {
+        info!("GET cab notifications FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only a cab gets notifications"));
+    }
+    debug!("GET cab notifications usr_id={}", auth.user_id());
+    return Ok(HttpResponse::Ok().json(take_notifications(get_auth_id(auth.user_id()))));
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Trips completed by the cab", body = OrderHistory),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/cabs/history")]
+async fn get_cab_history(
+    query: web::Query<HistoryQuery>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_put_cab(obj, auth, db_pool).await;
+    if !auth.user_id().starts_with("cab") {
+        info!("GET cab history FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only a cab has a history of trips served"));
+    }
+    info!("GET cab history usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    let q: HistoryQuery = query.into_inner();
+    return get_object(user_id, user_id, db_pool, |u, c, id| {
+        select_cab_history(u, c, id, q)
+    });
 }
-#[put("/cabs/")]
-async fn put_cab2(
-    obj: web::Json<Cab>,
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "The open shift, the cab becomes FREE", body = Shift),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/cabs/shift/start")]
+async fn post_start_shift(
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_put_cab(obj, auth, db_pool).await;
+    if !auth.user_id().starts_with("cab") {
+        info!("POST shift start FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only a cab has shifts"));
+    }
+    info!("POST shift start usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, start_shift);
 }
 
-#[put("/legs")]
-async fn put_leg(
-    obj: web::Json<Leg>,
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "The shift ended, Id=-1 if the cab is not on shift or still ASSIGNED", body = Shift),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/cabs/shift/end")]
+async fn post_end_shift(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    if !auth.user_id().starts_with("cab") {
+        info!("POST shift end FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only a cab has shifts"));
+    }
+    info!("POST shift end usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, end_shift);
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "The open shift, Id=-1 if the cab is not on shift or not FREE", body = Shift),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/cabs/shift/break/start")]
+async fn post_start_break(
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_put_leg(obj, auth, db_pool).await;
+    if !auth.user_id().starts_with("cab") {
+        info!("POST break start FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only a cab has shifts"));
+    }
+    info!("POST break start usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, start_break);
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "The open shift, the cab becomes FREE, Id=-1 if not on shift", body = Shift),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/cabs/shift/break/end")]
+async fn post_end_break(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+    if !auth.user_id().starts_with("cab") {
+        info!("POST break end FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only a cab has shifts"));
+    }
+    info!("POST break end usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, end_break);
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    params(ShiftQuery),
+    responses(
+        (status = 200, description = "Shifts of the cab with minutes worked and on breaks, the last 7 days by default", body = ShiftReport),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/cabs/{id}/shifts")]
+async fn get_cab_shifts(
+    id: web::Path<i64>,
+    query: web::Query<ShiftQuery>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let myid: i64 = id.into_inner();
+    if !is_admin(auth.user_id()) && auth.user_id() != format!("cab{}", myid) {
+        info!(
+            "GET cab shifts FORBIDDEN cab_id={} usr_id={}",
+            myid,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Not owner"));
+    }
+    info!("GET cab shifts cab_id={} usr_id={}", myid, auth.user_id());
+    let q: ShiftQuery = query.into_inner();
+    return get_object(get_auth_id(auth.user_id()), myid, db_pool, |u, c, id| {
+        select_shifts(u, c, id, q)
+    });
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    params(CabQuery),
+    responses(
+        (status = 200, description = "Cabs matching the filters, admin only", body = [CabInfo]),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/cabs")]
+async fn get_cabs(
+    query: web::Query<CabQuery>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(auth.user_id()) {
+        info!("GET cabs FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only an admin can list cabs"));
+    }
+    info!("GET cabs usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    let q: CabQuery = query.into_inner();
+    return get_object(user_id, user_id, db_pool, |u, c, _id| select_cabs(u, c, q));
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "Registered cab, Id -1 if refused", body = CabInfo),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/cabs")]
+async fn post_cab(
+    obj: web::Json<CabInfo>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let o: CabInfo = obj.into_inner();
+    if !is_admin(auth.user_id()) {
+        info!(
+            "POST cab FORBIDDEN cab_id={} usr_id={}",
+            o.cab.id,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Only an admin can register a cab"));
+    }
+    info!("POST cab cab_id={} usr_id={}", o.cab.id, auth.user_id());
+    return update_object(get_auth_id(auth.user_id()), o, db_pool, insert_cab);
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "Cab after the change", body = CabInfo),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[put("/cabs/{id}")]
+async fn put_cab_info(
+    id: web::Path<i64>,
+    obj: web::Json<CabUpdate>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let o: CabUpdate = obj.into_inner();
+    let myid: i64 = id.into_inner();
+    if !is_admin(auth.user_id()) {
+        info!(
+            "PUT cab info FORBIDDEN cab_id={} usr_id={}",
+            myid,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Only an admin can change a cab"));
+    }
+    info!(
+        "PUT cab info cab_id={} seats={:?} active={:?} usr_id={}",
+        myid,
+        o.seats,
+        o.active,
+        auth.user_id()
+    );
+    return get_object(get_auth_id(auth.user_id()), myid, db_pool, |u, c, id| {
+        update_cab_info(u, c, id, o)
+    });
+}
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "Deactivated cab", body = CabInfo),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/cabs/{id}/deactivate")]
+async fn post_deactivate_cab(
+    id: web::Path<i64>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let myid: i64 = id.into_inner();
+    if !is_admin(auth.user_id()) {
+        info!(
+            "POST deactivate cab FORBIDDEN cab_id={} usr_id={}",
+            myid,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Only an admin can deactivate a cab"));
+    }
+    info!(
+        "POST deactivate cab cab_id={} usr_id={}",
+        myid,
+        auth.user_id()
+    );
+    return get_object(get_auth_id(auth.user_id()), myid, db_pool, deactivate_cab);
 }
-#[put("/legs/")]
-async fn put_leg2(
+
+#[utoipa::path(
+    tag = "cabs",
+    responses(
+        (status = 200, description = "Location and status updated by the cab itself", body = Cab),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[put("/cabs")]
+async fn put_cab(
+    obj: web::Json<Cab>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    return just_put_cab(obj, auth, db_pool).await;
+}
+#[utoipa::path(
+    tag = "routes",
+    responses(
+        (status = 200, description = "Leg updated by the cab", body = Leg),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[put("/legs")]
+async fn put_leg(
     obj: web::Json<Leg>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
     return just_put_leg(obj, auth, db_pool).await;
 }
-
+#[utoipa::path(
+    tag = "routes",
+    responses(
+        (status = 200, description = "Route with legs and the assigned cab", body = Route),
+    )
+)]
 #[get("/routes/{id}")]
 async fn get_route_by_id(
     id: web::Path<i64>,
@@ -186,14 +646,22 @@ async fn get_route_by_id(
     );
 }
 
+#[utoipa::path(
+    tag = "routes",
+    responses(
+        (status = 200, description = "Route the cab should follow", body = Route),
+    )
+)]
 #[get("/routes")] // id will come from auth
 async fn get_route(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
     return just_get_route(auth, db_pool).await;
 }
-#[get("/routes/")] // id will come from auth
-async fn get_route2(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
-    return just_get_route(auth, db_pool).await;
-}
+#[utoipa::path(
+    tag = "routes",
+    responses(
+        (status = 200, description = "Route of the cab with passengers", body = RouteWithOrders),
+    )
+)]
 #[get("/routewithorders")] // just to keep compatibility with Java
 async fn get_route_with_orders(
     auth: BasicAuth,
@@ -201,88 +669,162 @@ async fn get_route_with_orders(
 ) -> Result<HttpResponse, Error> {
     return just_get_route_with_orders(auth, db_pool).await;
 }
-#[get("/routewithorders/")] // just to keep compatibility with Java
-async fn get_route_with_orders2(
-    auth: BasicAuth,
-    db_pool: web::Data<Pool>,
-) -> Result<HttpResponse, Error> {
-    return just_get_route_with_orders(auth, db_pool).await;
-}
-
+#[utoipa::path(
+    tag = "kaut",
+    params(("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key gets the first response")),
+    responses(
+        (status = 200, description = "True if the order has been registered", body = bool),
+    )
+)]
 #[post("/assignfreecab")]
 async fn post_assign_free_cab(
+    req: HttpRequest,
     obj: web::Json<CabAssign>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_assign_free_cab(obj, auth, db_pool).await;
+    return just_assign_free_cab(req, obj, auth, db_pool).await;
 }
 
-#[post("/assignfreecab/")]
-async fn post_assign_free_cab2(
+#[utoipa::path(
+    tag = "kaut",
+    params(("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key gets the first response")),
+    responses(
+        (status = 200, description = "True if the customer has joined the route", body = bool),
+    )
+)]
+#[post("/assigntoroute")]
+async fn post_assign_to_route(
+    req: HttpRequest,
     obj: web::Json<CabAssign>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_assign_free_cab(obj, auth, db_pool).await;
+    return just_assign_to_route(req, obj, auth, db_pool).await;
 }
 
-#[post("/assigntoroute")]
-async fn post_assign_to_route(
-    obj: web::Json<CabAssign>,
+#[utoipa::path(
+    tag = "routes",
+    responses(
+        (status = 200, description = "Route updated by the cab", body = Route),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[put("/routes")]
+async fn put_route(
+    obj: web::Json<Route>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_assign_to_route(obj, auth, db_pool).await;
+    return just_put_route(obj, auth, db_pool).await;
 }
-
-#[post("/assigntoroute/")]
-async fn post_assign_to_route2(
-    obj: web::Json<CabAssign>,
+#[utoipa::path(
+    tag = "orders",
+    responses(
+        (status = 200, description = "Order with the assigned cab", body = Order),
+    )
+)]
+#[get("/orders/{id}")]
+async fn get_order(
+    id: web::Path<i64>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_assign_to_route(obj, auth, db_pool).await;
+    let myid: i64 = id.abs(); // TODO: how to unwrap?
+    info!("GET order order_id={} usr_id={}", myid, auth.user_id());
+    return get_object(get_auth_id(auth.user_id()), myid, db_pool, select_order);
 }
 
-#[put("/routes")]
-async fn put_route(
-    obj: web::Json<Route>,
+#[utoipa::path(
+    tag = "orders",
+    responses(
+        (status = 200, description = "Trips booked for later, the nearest first", body = [Order]),
+    )
+)]
+#[get("/orders/upcoming")]
+async fn get_upcoming_orders(
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_put_route(obj, auth, db_pool).await;
+    info!("GET upcoming orders usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, select_upcoming_orders);
 }
-#[put("/routes/")]
-async fn put_route2(
-    obj: web::Json<Route>,
+
+#[utoipa::path(
+    tag = "orders",
+    responses(
+        (status = 200, description = "Order after cancellation, status not CANCELLED if it was too late", body = Order),
+    )
+)]
+#[post("/orders/{id}/cancel")]
+async fn post_cancel_order(
+    id: web::Path<i64>,
+    obj: Option<web::Json<OrderCancel>>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_put_route(obj, auth, db_pool).await;
+    let mut o: OrderCancel = match obj {
+        Some(o) => o.into_inner(),
+        None => OrderCancel {
+            id: -1,
+            reason: None,
+        },
+    };
+    o.id = id.into_inner();
+    info!(
+        "POST cancel order order_id={} usr_id={}",
+        o.id,
+        auth.user_id()
+    );
+    let user_id: i64 = get_auth_id(auth.user_id());
+    let myid: i64 = o.id;
+    return get_object(user_id, myid, db_pool, |u, c, _id| cancel_order(u, c, o));
 }
 
-#[get("/orders/{id}")]
-async fn get_order(
-    id: web::Path<i64>,
+#[utoipa::path(
+    tag = "orders",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "Past trips of the customer", body = OrderHistory),
+    )
+)]
+#[get("/orders/history")]
+async fn get_order_history(
+    query: web::Query<HistoryQuery>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    let myid: i64 = id.abs(); // TODO: how to unwrap?
-    info!("GET order order_id={} usr_id={}", myid, auth.user_id());
-    return get_object(get_auth_id(auth.user_id()), myid, db_pool, select_order);
+    info!("GET order history usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    let q: HistoryQuery = query.into_inner();
+    return get_object(user_id, user_id, db_pool, |u, c, id| {
+        select_customer_history(u, c, id, q)
+    });
 }
 
+#[utoipa::path(
+    tag = "orders",
+    params(OrdersQuery),
+    responses(
+        (status = 200, description = "Active orders of the customer, with upcoming=true its trips booked for later", body = [Order]),
+    )
+)]
 #[get("/orders")]
-async fn get_order2(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
-    return just_get_orders(auth, db_pool).await;
-}
-
-#[get("/orders/")]
-async fn get_order3(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
-    return just_get_orders(auth, db_pool).await;
+async fn get_orders(
+    query: web::Query<OrdersQuery>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    return just_get_orders(query.into_inner(), auth, db_pool).await;
 }
 
+#[utoipa::path(
+    tag = "orders",
+    responses(
+        (status = 200, description = "Order after the change of status", body = Order),
+    )
+)]
 #[put("/orders")]
 async fn put_order(
     obj: web::Json<Order>,
@@ -291,41 +833,143 @@ async fn put_order(
 ) -> Result<HttpResponse, Error> {
     return just_put_order(obj, auth, db_pool).await;
 }
-#[put("/orders/")]
-async fn put_order2(
+#[utoipa::path(
+    tag = "orders",
+    params(("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key gets the first response")),
+    responses(
+        (status = 200, description = "Registered order, Id -1 and status REFUSED if refused", body = Order),
+    )
+)]
+#[post("/orders")]
+async fn post_order(
+    req: HttpRequest,
     obj: web::Json<Order>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_put_order(obj, auth, db_pool).await;
+    return just_post_order(req, obj, auth, db_pool).await;
 }
-
-#[post("/orders")]
-async fn post_order(
-    obj: web::Json<Order>,
+#[utoipa::path(
+    tag = "stops",
+    responses(
+        (status = 200, description = "All active stops", body = [Stop]),
+    )
+)]
+#[get("/stops")]
+async fn get_stops() -> Result<HttpResponse, Error> {
+    return Ok(HttpResponse::Ok().json(STOPS.read().unwrap().clone()));
+}
+#[utoipa::path(
+    tag = "stops",
+    responses(
+        (status = 200, description = "Added stop, id -1 if refused", body = Stop),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/stops")]
+async fn post_stop(
+    obj: web::Json<Stop>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_post_order(obj, auth, db_pool).await;
+    let o: Stop = obj.into_inner();
+    if !is_admin(auth.user_id()) {
+        info!(
+            "POST stop FORBIDDEN stop_id={} usr_id={}",
+            o.id,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Only an admin can add a stop"));
+    }
+    info!("POST stop stop_id={} usr_id={}", o.id, auth.user_id());
+    return update_object(get_auth_id(auth.user_id()), o, db_pool, insert_stop);
 }
-#[post("/orders/")]
-async fn post_order2(
-    obj: web::Json<Order>,
+
+#[utoipa::path(
+    tag = "stops",
+    responses(
+        (status = 200, description = "Changed stop, id -1 if refused", body = Stop),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[put("/stops/{id}")]
+async fn put_stop(
+    id: web::Path<i64>,
+    obj: web::Json<Stop>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    return just_post_order(obj, auth, db_pool).await;
+    let mut o: Stop = obj.into_inner();
+    o.id = id.into_inner();
+    if !is_admin(auth.user_id()) {
+        info!(
+            "PUT stop FORBIDDEN stop_id={} usr_id={}",
+            o.id,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Only an admin can move a stop"));
+    }
+    info!("PUT stop stop_id={} usr_id={}", o.id, auth.user_id());
+    return update_object(get_auth_id(auth.user_id()), o, db_pool, update_stop);
 }
 
-#[get("/stops")]
-async fn get_stops() -> Result<HttpResponse, Error> {
-    return Ok(HttpResponse::Ok().json(unsafe { STOPS.clone() }));
+#[utoipa::path(
+    tag = "stops",
+    responses(
+        (status = 200, description = "Retired stop, id -1 if not found", body = Stop),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/stops/{id}/retire")]
+async fn post_retire_stop(
+    id: web::Path<i64>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let myid: i64 = id.into_inner();
+    if !is_admin(auth.user_id()) {
+        info!(
+            "POST retire stop FORBIDDEN stop_id={} usr_id={}",
+            myid,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Only an admin can retire a stop"));
+    }
+    info!(
+        "POST retire stop stop_id={} usr_id={}",
+        myid,
+        auth.user_id()
+    );
+    return get_object(get_auth_id(auth.user_id()), myid, db_pool, retire_stop);
 }
-#[get("/stops/")]
-async fn get_stops2() -> Result<HttpResponse, Error> {
-    return Ok(HttpResponse::Ok().json(unsafe { STOPS.clone() }));
+
+#[utoipa::path(
+    tag = "stops",
+    responses(
+        (status = 200, description = "Number of stops loaded", body = usize),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[post("/stops/reload")]
+async fn post_reload_stops(
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(auth.user_id()) {
+        info!("POST reload stops FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only an admin can reload stops"));
+    }
+    info!("POST reload stops usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, reload_stops);
 }
 
+#[utoipa::path(
+    tag = "stops",
+    responses(
+        (status = 200, description = "Routes and free cabs at the stop", body = StopTraffic),
+    )
+)]
 #[get("/stops/{id}/traffic")]
 async fn get_traffic(
     id: web::Path<i64>,
@@ -338,12 +982,153 @@ async fn get_traffic(
     return get_object(get_auth_id(auth.user_id()), myid, db_pool, select_traffik);
 }
 
+#[utoipa::path(
+    tag = "orders",
+    responses(
+        (status = 200, description = "Trip time, pickup ETA and fares, nothing is booked", body = Quote),
+        (status = 400, description = "Unknown stops or no cab for so many passengers"),
+    )
+)]
+#[post("/quotes")]
+async fn post_quote(
+    obj: web::Json<QuoteRequest>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let q: QuoteRequest = obj.into_inner();
+    info!(
+        "POST quote from={} to={} shared={} usr_id={}",
+        q.from,
+        q.to,
+        q.shared,
+        auth.user_id()
+    );
+    if q.from == q.to || get_stop(q.from as i64).is_none() || get_stop(q.to as i64).is_none() {
+        return Ok(HttpResponse::BadRequest().json("Unknown or the same stops"));
+    }
+    if q.passengers < 1 {
+        return Ok(HttpResponse::BadRequest().json("Wrong number of passengers"));
+    }
+    let user_id: i64 = get_auth_id(auth.user_id());
+    return get_object(user_id, user_id, db_pool, |u, c, _id| select_quote(u, c, q));
+}
+
+#[utoipa::path(
+    tag = "stats",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "KPIs, orders and cabs by status", body = Stats),
+        (status = 400, description = "Unknown window"),
+    )
+)]
 #[get("/stats")]
-async fn get_stats(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+async fn get_stats(
+    query: web::Query<StatsQuery>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
     // -> impl Responder
     info!("GET stats for usr_id={}", auth.user_id());
     let user_id: i64 = get_auth_id(auth.user_id());
-    return get_object(user_id, user_id, db_pool, select_stats);
+    let q: StatsQuery = query.into_inner();
+    if let Some(w) = &q.window {
+        if window_slots(w).is_none() {
+            return Ok(HttpResponse::BadRequest().json("Window can be 1m, 15m or 1h"));
+        }
+    }
+    return get_object(user_id, user_id, db_pool, |u, c, _id| select_stats(u, c, q));
+}
+
+#[utoipa::path(
+    tag = "stats",
+    params(LeaderboardQuery),
+    responses(
+        (status = 200, description = "Reports of active cabs sorted by a KPI, admin only", body = [Report]),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/stats/cabs/leaderboard")]
+async fn get_leaderboard(
+    query: web::Query<LeaderboardQuery>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    if !is_admin(auth.user_id()) {
+        info!("GET leaderboard FORBIDDEN usr_id={}", auth.user_id());
+        return Ok(HttpResponse::Forbidden().json("Only an admin can compare cabs"));
+    }
+    info!("GET leaderboard usr_id={}", auth.user_id());
+    let user_id: i64 = get_auth_id(auth.user_id());
+    let q: LeaderboardQuery = query.into_inner();
+    return get_object(user_id, user_id, db_pool, |u, c, _id| {
+        select_leaderboard(u, c, q)
+    });
+}
+
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Trips, kilometres, idle time, occupancy and delay of a cab", body = Report),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/stats/cabs/{id}")]
+async fn get_cab_report(
+    id: web::Path<i64>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let myid: i64 = id.into_inner();
+    if !is_admin(auth.user_id()) && auth.user_id() != format!("cab{}", myid) {
+        info!(
+            "GET cab report FORBIDDEN cab_id={} usr_id={}",
+            myid,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Not owner"));
+    }
+    info!("GET cab report cab_id={} usr_id={}", myid, auth.user_id());
+    return get_object(
+        get_auth_id(auth.user_id()),
+        myid,
+        db_pool,
+        select_cab_report,
+    );
+}
+
+#[utoipa::path(
+    tag = "stats",
+    responses(
+        (status = 200, description = "Trips, kilometres, wait, occupancy and delay of a customer", body = Report),
+        (status = 403, description = "Not allowed for this user"),
+    )
+)]
+#[get("/stats/customers/{id}")]
+async fn get_customer_report(
+    id: web::Path<i64>,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let myid: i64 = id.into_inner();
+    if !is_admin(auth.user_id()) && auth.user_id() != format!("cust{}", myid) {
+        info!(
+            "GET customer report FORBIDDEN cust_id={} usr_id={}",
+            myid,
+            auth.user_id()
+        );
+        return Ok(HttpResponse::Forbidden().json("Not owner"));
+    }
+    info!(
+        "GET customer report cust_id={} usr_id={}",
+        myid,
+        auth.user_id()
+    );
+    return get_object(
+        get_auth_id(auth.user_id()),
+        myid,
+        db_pool,
+        select_customer_report,
+    );
 }
 
 async fn just_put_cab(
@@ -374,21 +1159,29 @@ async fn just_put_cab(
 }
 
 async fn just_assign_free_cab(
+    req: HttpRequest,
     obj: web::Json<CabAssign>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
     let o: CabAssign = obj.into_inner();
-    return insert_object(get_auth_id(auth.user_id()), o, db_pool, assign_free_cab);
+    return idempotent(&req, auth.user_id(), &o, || {
+        insert_object(get_auth_id(auth.user_id()), o, db_pool, assign_free_cab)
+    })
+    .await;
 }
 
 async fn just_assign_to_route(
+    req: HttpRequest,
     obj: web::Json<CabAssign>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
     let o: CabAssign = obj.into_inner();
-    return insert_object(get_auth_id(auth.user_id()), o, db_pool, assign_to_route);
+    return idempotent(&req, auth.user_id(), &o, || {
+        insert_object(get_auth_id(auth.user_id()), o, db_pool, assign_to_route)
+    })
+    .await;
 }
 
 async fn just_put_leg(
@@ -430,9 +1223,20 @@ async fn just_get_route_with_orders(
     return get_object(user_id, user_id, db_pool, select_route_with_orders);
 }
 
-async fn just_get_orders(auth: BasicAuth, db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
-    info!("GET orders usr_id={}", auth.user_id());
+async fn just_get_orders(
+    q: OrdersQuery,
+    auth: BasicAuth,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    info!(
+        "GET orders upcoming={:?} usr_id={}",
+        q.upcoming,
+        auth.user_id()
+    );
     let user_id: i64 = get_auth_id(auth.user_id());
+    if q.upcoming == Some(true) {
+        return get_object(user_id, user_id, db_pool, select_upcoming_orders);
+    }
     return get_object(user_id, user_id, db_pool, select_orders); // get_object2
 }
 
@@ -477,6 +1281,7 @@ async fn just_put_order(
 }
 
 async fn just_post_order(
+    req: HttpRequest,
     obj: web::Json<Order>,
     auth: BasicAuth,
     db_pool: web::Data<Pool>,
@@ -490,7 +1295,10 @@ async fn just_post_order(
     );
     let user_id: i64 = get_auth_id(auth.user_id());
     o.cust_id = user_id; // authorisation ;)
-    return update_object(user_id, o, db_pool, insert_order);
+    return idempotent(&req, auth.user_id(), &o, || {
+        update_object(user_id, o, db_pool, insert_and_match_order)
+    })
+    .await;
 }
 
 fn get_object<T>(
@@ -500,18 +1308,16 @@ fn get_object<T>(
     f: impl FnOnce(i64, &mut PooledConn, i64) -> T,
 ) -> Result<HttpResponse, Error>
 where
-    T: Serialize,
+    T: Serialize + Outcome,
 {
-    match db_pool.get_conn() {
+    match get_conn(&db_pool) {
         Ok(mut c) => {
+            conn_taken();
             let obj: T = f(user_id, &mut c, object_id) as T;
-            return Ok(HttpResponse::Ok().json(obj));
-        }
-        Err(err) => {
-            return Ok(HttpResponse::Ok()
-                .insert_header(("Access-Control-Allow-Origin", "*"))
-                .json(format!("{}", err)));
+            conn_returned();
+            return Ok(respond(obj));
         }
+        Err(err) => return Ok(db_unavailable(err)),
     };
 }
 
@@ -522,16 +1328,16 @@ fn update_object<T>(
     f: impl FnOnce(i64, &mut PooledConn, T) -> T,
 ) -> Result<HttpResponse, Error>
 where
-    T: Serialize,
+    T: Serialize + Outcome,
 {
-    match db_pool.get_conn() {
+    match get_conn(&db_pool) {
         Ok(mut c) => {
+            conn_taken();
             let obj: T = f(user_id, &mut c, o) as T;
-            return Ok(HttpResponse::Ok().json(obj));
-        }
-        Err(err) => {
-            return Ok(HttpResponse::Ok().json(format!("{}", err)));
+            conn_returned();
+            return Ok(respond(obj));
         }
+        Err(err) => return Ok(db_unavailable(err)),
     };
 }
 
@@ -544,17 +1350,34 @@ fn insert_object<T>(
 where
     T: Serialize,
 {
-    match db_pool.get_conn() {
+    match get_conn(&db_pool) {
         Ok(mut c) => {
+            conn_taken();
             let obj: bool = f(user_id, &mut c, o) as bool;
-            return Ok(HttpResponse::Ok().json(obj));
-        }
-        Err(err) => {
-            return Ok(HttpResponse::Ok().json(format!("{}", err)));
+            conn_returned();
+            return Ok(respond(obj));
         }
+        Err(err) => return Ok(db_unavailable(err)),
     };
 }
 
+// v1 answers with the error and 200 as it always has, such an answer is not replayed
+fn db_unavailable(err: mysql::Error) -> HttpResponse {
+    let mut resp = if is_v2() {
+        HttpResponse::ServiceUnavailable().json(format!("{}", err))
+    } else {
+        HttpResponse::Ok()
+            .insert_header(("Access-Control-Allow-Origin", "*"))
+            .json(format!("{}", err))
+    };
+    resp.extensions_mut().insert(NoReplay);
+    return resp;
+}
+
+fn is_admin(id: &str) -> bool {
+    return id.starts_with("adm");
+}
+
 fn get_auth_id(id: &str) -> i64 {
     if id.len() < 4 {
         // cab0
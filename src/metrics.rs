@@ -0,0 +1,135 @@
+use crate::model::Stat;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use mysql::{Pool, PooledConn};
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+
+// exposed at /metrics in Prometheus text format
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "kapir_http_requests_total",
+        "Requests by handler, method and status",
+        &["handler", "method", "status"]
+    )
+    .unwrap()
+});
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "kapir_http_request_duration_seconds",
+        "Time of serving a request by handler and method",
+        &["handler", "method"]
+    )
+    .unwrap()
+});
+static DB_CONN_WAIT: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "kapir_db_pool_wait_seconds",
+        "Time of waiting for a connection from the DB pool"
+    )
+    .unwrap()
+});
+static DB_CONN_IN_USE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "kapir_db_pool_connections_in_use",
+        "Connections taken from the DB pool"
+    )
+    .unwrap()
+});
+static DB_CONN_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "kapir_db_pool_errors_total",
+        "Failures of getting a connection from the DB pool"
+    )
+    .unwrap()
+});
+static DB_QUERY_ERRORS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("kapir_db_query_errors_total", "Failed DB updates").unwrap()
+});
+static ORDERS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("kapir_orders", "Orders by status", &["status"]).unwrap()
+});
+static CABS: LazyLock<IntGaugeVec> =
+    LazyLock::new(|| register_int_gauge_vec!("kapir_cabs", "Cabs by status", &["status"]).unwrap());
+
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let res = next.call(req).await;
+    let (handler, status) = match &res {
+        Ok(r) => (
+            r.request()
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".to_string()),
+            r.status().as_u16().to_string(),
+        ),
+        Err(err) => (
+            "unmatched".to_string(),
+            err.as_response_error().status_code().as_u16().to_string(),
+        ),
+    };
+    HTTP_REQUESTS
+        .with_label_values(&[&handler, &method, &status])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[&handler, &method])
+        .observe(start.elapsed().as_secs_f64());
+    return res;
+}
+
+pub fn get_conn(pool: &Pool) -> mysql::Result<PooledConn> {
+    let timer = DB_CONN_WAIT.start_timer();
+    let res = pool.get_conn();
+    timer.observe_duration();
+    if res.is_err() {
+        DB_CONN_ERRORS.inc();
+    }
+    return res;
+}
+
+pub fn conn_taken() {
+    DB_CONN_IN_USE.inc();
+}
+
+pub fn conn_returned() {
+    DB_CONN_IN_USE.dec();
+}
+
+pub fn query_failed() {
+    DB_QUERY_ERRORS.inc();
+}
+
+// 'orders' and 'cabs' as counted for /stats
+pub fn encode_metrics(orders: Vec<Stat>, cabs: Vec<Stat>) -> String {
+    ORDERS.reset();
+    for s in orders {
+        ORDERS.with_label_values(&[&s.name]).set(s.int_val as i64);
+    }
+    CABS.reset();
+    for s in cabs {
+        CABS.with_label_values(&[&s.name]).set(s.int_val as i64);
+    }
+    // make sure all are there even before the first request
+    LazyLock::force(&HTTP_REQUESTS);
+    LazyLock::force(&HTTP_DURATION);
+    LazyLock::force(&DB_CONN_WAIT);
+    LazyLock::force(&DB_CONN_IN_USE);
+    LazyLock::force(&DB_CONN_ERRORS);
+    LazyLock::force(&DB_QUERY_ERRORS);
+
+    let mut buffer: Vec<u8> = vec![];
+    if let Err(err) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        return format!("# {}\n", err);
+    }
+    return String::from_utf8(buffer).unwrap_or_default();
+}
@@ -1,18 +1,101 @@
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct Cab {
+    #[serde(skip_serializing_if = "crate::version::absent")]
     pub id: i64,
+    #[serde(skip_serializing_if = "crate::version::absent")]
     pub location: i32,
+    #[serde(skip_serializing_if = "crate::version::absent")]
     pub status: CabStatus,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub seats: i8,
+    #[serde(default)]
+    pub features: Features,
+}
+
+// What a vehicle offers and what an order needs. Kern reads them from the DB as bit masks,
+// 'features' of cab and 'requires' of taxi_order and freetaxi_order, see to_mask
+#[derive(Debug, Copy, Clone, Default, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct Features {
+    #[serde(default)]
+    pub wheelchair: bool,
+    #[serde(default)]
+    pub luggage: bool,
+    #[serde(default)]
+    pub child_seat: bool,
+    #[serde(default)]
+    pub electric: bool,
+}
+
+impl Features {
+    pub fn to_mask(self) -> i32 {
+        return self.wheelchair as i32
+            | (self.luggage as i32) << 1
+            | (self.child_seat as i32) << 2
+            | (self.electric as i32) << 3;
+    }
+
+    pub fn from_mask(mask: i32) -> Features {
+        return Features {
+            wheelchair: mask & 1 != 0,
+            luggage: mask & 2 != 0,
+            child_seat: mask & 4 != 0,
+            electric: mask & 8 != 0,
+        };
+    }
+
+    // a vehicle with these features can serve an order with 'required'
+    pub fn covers(&self, required: &Features) -> bool {
+        return required.to_mask() & !self.to_mask() == 0;
+    }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+// admin's view of a cab
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct CabInfo {
+    #[serde(flatten)]
+    pub cab: Cab,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default = "default_active")]
+    pub active: bool, // a deactivated cab cannot report itself FREE
+}
+
+pub fn default_active() -> bool {
+    return true;
+}
+
+// admin's PUT /cabs/{id}, only what is sent is changed
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct CabUpdate {
+    #[serde(default)]
+    pub seats: Option<i8>,
+    #[serde(default)]
+    pub features: Option<Features>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub active: Option<bool>,
+}
+
+// filters of admin's GET /cabs, all optional
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CabQuery {
+    pub status: Option<CabStatus>,
+    pub location: Option<i32>,
+    pub active: Option<bool>,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct CabAssign {
     pub cust_id: i64,
@@ -20,14 +103,62 @@ pub struct CabAssign {
     pub to: i32,
     pub loss: i32,
     pub shared: bool,
+    #[serde(default = "default_passengers")]
+    pub passengers: i32,
+    #[serde(default)]
+    pub requires: Features,
+}
+
+// one customer travels alone unless told otherwise
+pub fn default_passengers() -> i32 {
+    return 1;
+}
+
+fn default_fare() -> i64 {
+    return -1;
 }
 
 #[repr(i32)]
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
 pub enum CabStatus {
     ASSIGNED = 0,
     FREE = 1,
-    CHARGING = 2, // out of order, ...
+    CHARGING = 2,
+    OFFLINE = 3,     // not on shift
+    ONBREAK = 4,     // on shift but not taking orders
+    MAINTENANCE = 5, // out of order
+    UNKNOWN = -1,    // no cab, e.g. of an order not assigned yet; never stored
+}
+
+// all a cab can be in, in the order of stats
+pub const CAB_STATUSES: [CabStatus; 6] = [
+    CabStatus::ASSIGNED,
+    CabStatus::FREE,
+    CabStatus::CHARGING,
+    CabStatus::OFFLINE,
+    CabStatus::ONBREAK,
+    CabStatus::MAINTENANCE,
+];
+
+impl CabStatus {
+    // what a cab can change itself with PUT /cabs; ASSIGNED comes from Kern or the dispatcher,
+    // OFFLINE and ONBREAK from shift and break requests
+    pub fn can_become(&self, to: CabStatus) -> bool {
+        if *self == to {
+            return true; // a new location only
+        }
+        return match (self, to) {
+            (CabStatus::ASSIGNED, CabStatus::FREE) => true, // the route is done
+            (CabStatus::ASSIGNED, _) | (CabStatus::UNKNOWN, _) => false,
+            (_, CabStatus::FREE) | (_, CabStatus::CHARGING) | (_, CabStatus::MAINTENANCE) => true,
+            _ => false,
+        };
+    }
+
+    // a break is taken from FREE only: not on a route, not twice, not instead of charging or a repair
+    pub fn can_start_break(&self) -> bool {
+        return *self == CabStatus::FREE;
+    }
 }
 
 impl fmt::Display for CabStatus {
@@ -46,17 +177,18 @@ impl Default for Cab {
         Cab {
             id: -1,
             location: -1,
-            status: CabStatus::CHARGING,
+            status: CabStatus::UNKNOWN,
             seats: -1,
+            features: Features::default(),
         }
     }
 }
 
 // ORDER
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct Order {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub id: i64,
     pub from: i32,
     pub to: i32,
@@ -70,24 +202,40 @@ pub struct Order {
     pub in_pool: bool,
     #[serde(default)]
     pub status: OrderStatus,
-    #[serde(default)]
-    pub received: Option<NaiveDateTime>,
-    #[serde(default)]
-    pub started: Option<NaiveDateTime>,
-    #[serde(default)]
-    pub completed: Option<NaiveDateTime>,
-    #[serde(default)]
-    pub at_time: Option<NaiveDateTime>,
-    #[serde(default)]
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub received: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub started: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub completed: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub at_time: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub eta: i32,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub cab: Cab,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub cust_id: i64,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub route_id: i64,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub leg_id: i64,
+    #[serde(default = "default_passengers")]
+    pub passengers: i32,
+    // cents, estimated when ordered and counted on completion, -1 (left out in v2) if not known
+    #[serde(
+        default = "default_fare",
+        skip_serializing_if = "crate::version::absent"
+    )]
+    pub est_fare: i64,
+    #[serde(
+        default = "default_fare",
+        skip_serializing_if = "crate::version::absent"
+    )]
+    pub fare: i64,
+    // vehicle features the customer needs
+    #[serde(default)]
+    pub requires: Features,
     //    #[serde(default)]
     //    pub route: Route,
     //    #[serde(default)]
@@ -95,7 +243,7 @@ pub struct Order {
 }
 
 #[repr(i32)]
-#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
 pub enum OrderStatus {
     RECEIVED = 0,
     ASSIGNED = 1,
@@ -139,11 +287,16 @@ impl Default for Order {
             cab: Cab {
                 id: -1,
                 location: -1,
-                status: CabStatus::CHARGING,
+                status: CabStatus::UNKNOWN,
                 seats: -1,
+                features: Features::default(),
             },
             route_id: -1,
             leg_id: -1,
+            passengers: 1,
+            est_fare: -1,
+            fare: -1,
+            requires: Features::default(),
             //    route: Route { ..Default::default() },
             //    leg: Leg { ..Default::default()},
             cust_id: -1,
@@ -157,8 +310,55 @@ impl fmt::Display for OrderStatus {
     }
 }
 
+// body of POST /orders/{id}/cancel, the id comes from the path
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct OrderCancel {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// a message for a cab, e.g. a passenger has cancelled
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct Notification {
+    #[serde(with = "crate::timestamp")]
+    pub time: DateTime<Utc>,
+    pub message: String,
+}
+
+// GET /orders?upcoming=true lists the trips booked for later instead of the ones for now
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OrdersQuery {
+    pub upcoming: Option<bool>,
+}
+
+// filters of GET /orders/history and /cabs/history, all optional
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub to: Option<DateTime<Utc>>,
+    pub status: Option<OrderStatus>,
+    pub page: Option<i64>, // starts with 1
+    pub size: Option<i64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct OrderHistory {
+    pub total: i64, // all orders matching the filter, not only on this page
+    pub page: i64,
+    pub size: i64,
+    pub orders: Vec<Order>,
+}
+
 // STOP
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub struct Stop {
     pub id: i64,
     pub bearing: i32,
@@ -167,8 +367,20 @@ pub struct Stop {
     pub name: Option<String>,
 }
 
+impl Default for Stop {
+    fn default() -> Stop {
+        Stop {
+            id: -1,
+            bearing: 0,
+            latitude: 0.0,
+            longitude: 0.0,
+            name: None,
+        }
+    }
+}
+
 // LEG
-#[derive(Copy, Clone, Deserialize, Serialize)]
+#[derive(Copy, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct Leg {
     pub id: i64,
@@ -182,17 +394,17 @@ pub struct Leg {
     pub place: i32,
     #[serde(default)]
     pub dist: i32,
-    #[serde(default)]
-    pub started: Option<NaiveDateTime>,
-    #[serde(default)]
-    pub completed: Option<NaiveDateTime>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub started: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub completed: Option<DateTime<Utc>>,
     pub status: RouteStatus,
     #[serde(default)]
     pub passengers: i32,
 }
 
 #[repr(i32)]
-#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
 pub enum RouteStatus {
     PLANNED = 0,   // proposed by Pool
     ASSIGNED = 1,  // not confirmed, initial status
@@ -220,14 +432,15 @@ pub fn get_route_status(idx: i32) -> RouteStatus {
     return s;
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "PascalCase")]
 pub struct Route {
+    #[serde(skip_serializing_if = "crate::version::absent")]
     pub id: i64,
     pub status: RouteStatus,
     #[serde(default)]
     pub legs: Vec<Leg>,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "crate::version::absent")]
     pub cab: Cab,
 }
 
@@ -244,35 +457,153 @@ impl Default for Route {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub struct RouteWithOrders {
     pub route: Route,
     pub orders: Vec<Order>,
+    #[serde(skip_serializing_if = "crate::version::absent")]
     pub cab: Cab, // for Kaut app
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub struct StopTraffic {
     pub stop: Option<Stop>,
     pub routes: Vec<RouteWithEta>,
     pub cabs: Vec<Cab>,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub struct RouteWithEta {
     pub eta: i16,
     pub route: Route,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub struct Stats {
     pub kpis: Vec<Stat>,
     pub orders: Vec<Stat>,
     pub cabs: Vec<Stat>,
+    // KPIs of the last 1m, 15m or 1h, only if asked for with ?window=
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<String>,
+    #[serde(default)]
+    pub windowed: Vec<Stat>,
+}
+
+// a working day of a driver, minutes worked do not include breaks
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct Shift {
+    pub id: i64,
+    pub cab_id: i64,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub started: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub ended: Option<DateTime<Utc>>,
+    pub worked: i64,
+    pub breaks: i64,
+}
+
+// GET /cabs/{id}/shifts, hours worked in a period
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct ShiftReport {
+    pub cab_id: i64,
+    pub shifts: Vec<Shift>,
+    pub worked: i64, // minutes
+    pub breaks: i64,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ShiftQuery {
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub from: Option<DateTime<Utc>>, // the last 7 days if missing
+    #[serde(default, with = "crate::timestamp::opt")]
+    pub to: Option<DateTime<Utc>>,
+}
+
+// POST /quotes - what a trip would take and cost, nothing is booked
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct QuoteRequest {
+    pub from: i32,
+    pub to: i32,
+    #[serde(default)]
+    pub shared: bool,
+    #[serde(default = "default_passengers")]
+    pub passengers: i32,
+    #[serde(default)]
+    pub wait: Option<i32>, // max_wait and max_loss as in an order, no limit if missing
+    #[serde(default)]
+    pub loss: Option<i32>,
+    #[serde(default)]
+    pub requires: Features,
+}
+
+// minutes and cents, -1 if there is no estimate
+#[derive(Debug, Copy, Clone, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct Quote {
+    pub from: i32,
+    pub to: i32,
+    pub passengers: i32,
+    pub trip_time: i32, // direct
+    #[serde(skip_serializing_if = "crate::version::absent")]
+    pub eta: i32, // the nearest FREE cab
+    #[serde(skip_serializing_if = "crate::version::absent")]
+    pub pool_eta: i32, // a route passing by that could take the customer, only if 'shared'
+    #[serde(skip_serializing_if = "crate::version::absent")]
+    pub pool_trip_time: i32,
+    pub solo_fare: i64,
+    pub shared_fare: i64,
+}
+
+// GET /stats/cabs/{id}, /stats/customers/{id} and the leaderboard; times in seconds.
+// For a cab 'idle_time' is the time on shift, breaks excluded, not spent driving. For a customer 'idle_time' is the time spent waiting for pickups and 'occupancy'
+// counts passengers in the cab on the legs they rode
+#[derive(Debug, Copy, Clone, Default, Deserialize, Serialize, ToSchema)]
+pub struct Report {
+    pub id: i64,
+    pub trips: i64,
+    pub km: f64,
+    pub idle_time: i64,
+    pub occupancy: f64, // average passengers per leg
+    pub avg_delay: i64, // pickup later than the customer's max_wait
+}
+
+// GET /stats/cabs/leaderboard?by=idle&order=desc
+#[derive(Debug, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LeaderboardQuery {
+    pub by: Option<String>,    // trips (default), km, idle, occupancy or delay
+    pub order: Option<String>, // desc (default) or asc
+    pub limit: Option<i64>,
+}
+
+// GET /stats?window=15m
+#[derive(Debug, Clone, Deserialize, Serialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    pub window: Option<String>, // 1m, 15m or 1h
+}
+
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub struct Stat {
     pub name: String,
     pub int_val: i32,
 }
+
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct Health {
+    pub status: String,
+}
+
+// what /ready checks, all has to be true (and stops > 0) to serve requests
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub db: bool,
+    pub stops: usize,
+    pub distance: bool,
+}
@@ -0,0 +1,36 @@
+use crate::model::Notification;
+use chrono::Utc;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+// cabs are not connected all the time, messages wait here until the cab asks for them
+const MAX_PER_CAB: usize = 50;
+
+static NOTIFICATIONS: LazyLock<Mutex<HashMap<i64, Vec<Notification>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn notify_cab(cab_id: i64, message: String) {
+    if cab_id == -1 {
+        return;
+    }
+    debug!("notify_cab, cab_id={}, message={}", cab_id, message);
+    let mut notifications = NOTIFICATIONS.lock().unwrap();
+    let list = notifications.entry(cab_id).or_default();
+    if list.len() >= MAX_PER_CAB {
+        list.remove(0); // the oldest is the least interesting
+    }
+    list.push(Notification {
+        time: Utc::now(),
+        message,
+    });
+}
+
+// messages are given only once
+pub fn take_notifications(cab_id: i64) -> Vec<Notification> {
+    return NOTIFICATIONS
+        .lock()
+        .unwrap()
+        .remove(&cab_id)
+        .unwrap_or_default();
+}
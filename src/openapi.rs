@@ -0,0 +1,135 @@
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::Modify;
+
+// The one list of services under /v1, /v2 and the paths without version: it registers them
+// and puts them into the spec served at /openapi.json and /swagger-ui/, so the two cannot differ.
+// Used once in main, apart from the metrics and probes registered outside the scopes.
+macro_rules! services {
+    ($($service:ident),* $(,)?) => {
+        use crate::openapi::BasicAuthAddon;
+
+        // a slash at the end is trimmed by NormalizePath, no need to register paths twice
+        fn config_services(cfg: &mut web::ServiceConfig) {
+            $(cfg.service($service);)*
+        }
+
+        #[cfg(test)]
+        const SERVICES: &[&str] = &[$(stringify!($service)),*];
+
+        #[derive(OpenApi)]
+        #[openapi(
+            info(title = "Kapir", description = "Kabina Rest API"),
+            servers(
+                (url = "/v1", description = "Current version, -1 for missing values"),
+                (url = "/v2", description = "Missing values left out, 404 for what does not exist, 422 for a refused request"),
+                (url = "/", description = "Deprecated paths without version, kept for Kab and Kaut apps")
+            ),
+            paths(
+                crate::get_metrics,
+                crate::get_health,
+                crate::get_ready,
+                $(crate::$service),*
+            ),
+            components(schemas(
+                crate::model::Cab,
+                crate::model::CabAssign,
+                crate::model::CabInfo,
+                crate::model::CabUpdate,
+                crate::model::CabStatus,
+                crate::model::Features,
+                crate::model::Health,
+                crate::model::Leg,
+                crate::model::Notification,
+                crate::model::Order,
+                crate::model::OrderCancel,
+                crate::model::OrderHistory,
+                crate::model::OrderStatus,
+                crate::model::Quote,
+                crate::model::QuoteRequest,
+                crate::model::Readiness,
+                crate::model::Report,
+                crate::model::Route,
+                crate::model::RouteStatus,
+                crate::model::RouteWithEta,
+                crate::model::RouteWithOrders,
+                crate::model::Shift,
+                crate::model::ShiftReport,
+                crate::model::Stat,
+                crate::model::Stats,
+                crate::model::Stop,
+                crate::model::StopTraffic
+            )),
+            modifiers(&BasicAuthAddon),
+            security(("basic_auth" = []))
+        )]
+        pub struct ApiDoc;
+    };
+}
+
+// users are identified by the name: cab1, cust1, adm1
+pub(crate) struct BasicAuthAddon;
+
+impl Modify for BasicAuthAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "basic_auth",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config_services, ApiDoc, SERVICES};
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, App};
+    use utoipa::OpenApi;
+
+    // every operation in the spec reaches a service registered under the same pattern and method;
+    // the requests stop at the missing credentials or pool, a 404 or 405 means routing failed
+    #[actix_web::test]
+    async fn spec_matches_registered_services() {
+        let app = test::init_service(App::new().configure(config_services)).await;
+        let spec = ApiDoc::openapi();
+        let mut operations = 0;
+        for (path, item) in spec.paths.paths.iter() {
+            if ["/metrics", "/health", "/ready"].contains(&path.as_str()) {
+                continue; // registered outside the scopes
+            }
+            let methods = [
+                (Method::GET, &item.get),
+                (Method::PUT, &item.put),
+                (Method::POST, &item.post),
+                (Method::DELETE, &item.delete),
+                (Method::PATCH, &item.patch),
+            ];
+            for (method, _) in methods.into_iter().filter(|(_, op)| op.is_some()) {
+                operations += 1;
+                let uri = path.replace("{id}", "1");
+                let req = test::TestRequest::default()
+                    .method(method.clone())
+                    .uri(&uri)
+                    .to_request();
+                let resp = test::call_service(&app, req).await;
+                assert!(
+                    resp.status() != StatusCode::NOT_FOUND
+                        && resp.status() != StatusCode::METHOD_NOT_ALLOWED,
+                    "{} {} in OpenAPI spec is not registered",
+                    method,
+                    path
+                );
+                assert_eq!(
+                    resp.request().match_pattern().as_deref(),
+                    Some(path.as_str()),
+                    "{} {} is served by another pattern",
+                    method,
+                    path
+                );
+            }
+        }
+        // one route per service, none left out of the spec
+        assert_eq!(operations, SERVICES.len());
+    }
+}
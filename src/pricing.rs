@@ -0,0 +1,103 @@
+use crate::distance::CAB_SPEED;
+use crate::timestamp::display_zone;
+use chrono::{DateTime, Timelike, Utc};
+use std::collections::HashMap;
+
+// Fares are in cents of the local currency. A customer pays for the direct trip from DIST,
+// not for the way round; sharing is rewarded, more so when the detour has been longer.
+#[derive(Debug, Copy, Clone)]
+pub struct Tariff {
+    pub base_fare: i64,
+    pub fare_per_minute: i64,
+    pub fare_per_km: i64,
+    pub shared_discount: i64, // percent off every shared trip
+    pub detour_discount: i64, // percent of the detour (in percent) taken off too
+    pub max_discount: i64,
+    pub night_surcharge: i64, // percent
+    pub night_start: u32,     // hour in display_zone
+    pub night_end: u32,
+}
+
+// can be overwritten in kapir.toml, keys as the fields
+pub static mut TARIFF: Tariff = Tariff {
+    base_fare: 300,
+    fare_per_minute: 50,
+    fare_per_km: 100,
+    shared_discount: 20,
+    detour_discount: 50,
+    max_discount: 50,
+    night_surcharge: 20,
+    night_start: 22,
+    night_end: 6,
+};
+
+pub fn read_tariff(cfg: &HashMap<String, String>) {
+    let val = |key: &str| cfg.get(key).map(|v| v.parse::<i64>().unwrap());
+    unsafe {
+        if let Some(v) = val("base_fare") {
+            TARIFF.base_fare = v;
+        }
+        if let Some(v) = val("fare_per_minute") {
+            TARIFF.fare_per_minute = v;
+        }
+        if let Some(v) = val("fare_per_km") {
+            TARIFF.fare_per_km = v;
+        }
+        if let Some(v) = val("shared_discount") {
+            TARIFF.shared_discount = v;
+        }
+        if let Some(v) = val("detour_discount") {
+            TARIFF.detour_discount = v;
+        }
+        if let Some(v) = val("max_discount") {
+            TARIFF.max_discount = v;
+        }
+        if let Some(v) = val("night_surcharge") {
+            TARIFF.night_surcharge = v;
+        }
+        if let Some(v) = val("night_start") {
+            TARIFF.night_start = v as u32;
+        }
+        if let Some(v) = val("night_end") {
+            TARIFF.night_end = v as u32;
+        }
+    }
+}
+
+fn tariff() -> Tariff {
+    return unsafe { TARIFF };
+}
+
+// before the trip the detour is not known, only the shared discount is given
+pub fn estimate_fare(minutes: i32, shared: bool, at: DateTime<Utc>) -> i64 {
+    return count_fare(minutes, shared, 0, at);
+}
+
+// 'detour' in percent of the direct trip, 'at' is the pickup
+pub fn final_fare(minutes: i32, shared: bool, detour: i64, at: DateTime<Utc>) -> i64 {
+    return count_fare(minutes, shared, detour, at);
+}
+
+fn count_fare(minutes: i32, shared: bool, detour: i64, at: DateTime<Utc>) -> i64 {
+    let t = tariff();
+    // km from minutes in one expression, rounding down to whole km would drop up to a km per trip
+    let mut fare = t.base_fare
+        + t.fare_per_minute * minutes as i64
+        + t.fare_per_km * minutes as i64 * CAB_SPEED as i64 / 60;
+    if is_night(&t, at) {
+        fare += fare * t.night_surcharge / 100;
+    }
+    if shared {
+        let discount = t.shared_discount + detour.max(0) * t.detour_discount / 100;
+        fare -= fare * discount.min(t.max_discount) / 100;
+    }
+    return fare;
+}
+
+fn is_night(t: &Tariff, at: DateTime<Utc>) -> bool {
+    let hour = at.with_timezone(&display_zone()).hour();
+    if t.night_start > t.night_end {
+        return hour >= t.night_start || hour < t.night_end;
+    }
+    return hour >= t.night_start && hour < t.night_end;
+}
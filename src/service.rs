@@ -1,34 +1,57 @@
-use crate::distance::{DIST, STOPS};
+use crate::distance::{get_dist, get_stop, reload_distance, CAB_SPEED, MAXSTOPSNUMB, STOPS};
+use crate::metrics::query_failed;
 use crate::model::{
-    get_cab_status, get_order_status, get_route_status, Cab, CabAssign, CabStatus, Leg, Order,
-    OrderStatus, Route, RouteStatus, RouteWithEta, RouteWithOrders, Stat, Stats, Stop, StopTraffic,
+    get_cab_status, get_order_status, get_route_status, Cab, CabAssign, CabInfo, CabQuery,
+    CabStatus, CabUpdate, Features, HistoryQuery, LeaderboardQuery, Leg, Order, OrderCancel,
+    OrderHistory, OrderStatus, Quote, QuoteRequest, Report, Route, RouteStatus, RouteWithEta,
+    RouteWithOrders, Shift, ShiftQuery, ShiftReport, Stat, Stats, StatsQuery, Stop, StopTraffic,
+    CAB_STATUSES,
 };
-use crate::stats::{add_avg_complete, add_avg_pickup, save_status};
-use chrono::{Local, NaiveDateTime};
+use crate::notify::notify_cab;
+use crate::pricing::{estimate_fare, final_fare};
+use crate::stats::{
+    add_avg_complete, add_avg_detour, add_avg_detour_of_loss, add_avg_pickup, add_avg_wait,
+    add_avg_wait_of_max, add_completed_trip, save_status, window_averages, window_slots,
+};
+use crate::timestamp::from_db;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use log::{debug, info, warn};
 use mysql::prelude::*;
 use mysql::*;
+use std::collections::HashMap;
 use std::{cmp, usize};
 
 pub const STOP_WAIT: i32 = 1;
+// shifts are required to be FREE and drivers must have a break after MAX_DRIVING minutes, kapir.toml
+pub static mut SHIFTS_REQUIRED: bool = false;
+pub static mut MAX_DRIVING: i64 = 270;
+// how far ahead (in minutes) a customer can book a trip with AtTime, can be overwritten in kapir.toml
+pub static mut BOOKING_HORIZON: i64 = 7 * 24 * 60;
+// shared orders join a passing route at once instead of waiting for Kern, kapir.toml
+pub static mut POOL_MATCHING: bool = true;
+pub const HISTORY_PAGE_SIZE: i64 = 20;
+pub const HISTORY_MAX_PAGE_SIZE: i64 = 100;
+// minutes, a quote gives no ETA if no cab is free that near
+const QUOTE_RADIUS: i16 = 30;
 
 pub fn select_cab(user_id: i64, c: &mut PooledConn, id: i64) -> Cab {
     debug!("select_cab, user_id={}", user_id);
     let res = c.exec_map(
-        "SELECT location, status, seats FROM cab WHERE id=?",
+        "SELECT location, status, seats, features FROM cab WHERE id=?",
         (id,),
-        |(location, stat, seats)| Cab {
+        |(location, stat, seats, features)| Cab {
             id,
             location,
             status: get_cab_status(stat),
             seats,
+            features: Features::from_mask(features),
         },
     );
     match res {
-        Ok(rows) => {
+        Ok(rows) if !rows.is_empty() => {
             return rows[0];
         }
-        Err(_) => {
+        _ => {
             return Cab {
                 ..Default::default()
             }
@@ -39,13 +62,14 @@ pub fn select_cab(user_id: i64, c: &mut PooledConn, id: i64) -> Cab {
 pub fn select_cabs_by_stop(user_id: i64, c: &mut PooledConn, stop_id: i32) -> Vec<Cab> {
     debug!("select_cabs_by_stop, user_id={}", user_id);
     let res = c.exec_map(
-        "SELECT id, seats FROM cab WHERE location=? AND status=1",
+        "SELECT id, seats, features FROM cab WHERE location=? AND status=1",
         (stop_id,),
-        |(id, seats)| Cab {
+        |(id, seats, features)| Cab {
             id,
             location: stop_id,
             status: get_cab_status(1),
             seats,
+            features: Features::from_mask(features),
         },
     );
     match res {
@@ -56,20 +80,464 @@ pub fn select_cabs_by_stop(user_id: i64, c: &mut PooledConn, stop_id: i32) -> Ve
     }
 }
 
+// an active cab that can serve an order with these requirements, the fleet has no more
+fn fleet_covers(c: &mut PooledConn, required: &Features) -> bool {
+    let mask = required.to_mask();
+    if mask == 0 {
+        return true;
+    }
+    let res: Result<Option<i64>> = c.exec_first(
+        "SELECT COUNT(*) FROM cab WHERE active=true AND features & ? = ?",
+        (mask, mask),
+    );
+    return matches!(res, Ok(Some(count)) if count > 0);
+}
+
+// the biggest cab in the fleet, more passengers cannot be served by one order
+pub fn select_max_seats(c: &mut PooledConn) -> i32 {
+    let res: Result<Option<Option<i32>>> = c.query_first("SELECT MAX(seats) FROM cab");
+    return match res {
+        Ok(Some(Some(seats))) => seats,
+        _ => 0,
+    };
+}
+
 pub fn update_cab(user_id: i64, c: &mut PooledConn, cab: Cab) -> Cab {
-    if user_id == cab.id {
-        let res = c.exec_iter(
-            "UPDATE cab SET status=?, location=? WHERE id=?",
-            (cab.status as i32, cab.location, cab.id),
-        );
-        check_result(res);
-    } else {
+    if user_id != cab.id {
         info!(
             "update_cab not authorised, user_id={}, cab_id={}",
             user_id, cab.id
         );
+        return cab;
+    }
+    let res: Result<Option<(i32, bool)>> =
+        c.exec_first("SELECT status, active FROM cab WHERE id=?", (cab.id,));
+    let (current, active): (CabStatus, bool) = match res {
+        Ok(Some((status, active))) => (get_cab_status(status), active),
+        _ => {
+            info!("update_cab ignored, cab_id={} not found", cab.id);
+            return cab;
+        }
+    };
+    let mut status = cab.status;
+    if !active && status == CabStatus::FREE {
+        // a deactivated cab still reports its location and finishes its route
+        info!("update_cab FREE ignored, cab_id={} is deactivated", cab.id);
+        status = current;
+    } else if !current.can_become(status) {
+        info!(
+            "update_cab status ignored, cab_id={} cannot change {} to {}",
+            cab.id, current, status
+        );
+        status = current;
+    } else if status == CabStatus::FREE {
+        // a cab refused FREE still leaves ASSIGNED, otherwise it could not even end its shift
+        if let Some(instead) = status_instead_of_free(c, cab.id) {
+            status = instead;
+        }
+    }
+    // still, the location is worth knowing
+    let res = c.exec_iter(
+        "UPDATE cab SET status=?, location=? WHERE id=? AND status=? AND (active=true OR ?<>1)", // 1=FREE
+        (
+            status as i32,
+            cab.location,
+            cab.id,
+            current as i32,
+            status as i32,
+        ),
+    );
+    if check_result(res) == 0 {
+        info!(
+            "update_cab ignored, cab_id={} status changed meanwhile",
+            cab.id
+        );
+    }
+    let mut ret = cab;
+    ret.status = status;
+    return ret;
+}
+
+// None if the cab can be FREE: on shift, not on a break and not driving too long without one.
+// Otherwise what it is instead - OFFLINE without a shift, ONBREAK on a break; a break is started
+// when the driving time is over.
+fn status_instead_of_free(c: &mut PooledConn, cab_id: i64) -> Option<CabStatus> {
+    if !unsafe { SHIFTS_REQUIRED } {
+        return None;
+    }
+    let shift = match select_open_shift(c, cab_id) {
+        Some(s) => s,
+        None => {
+            info!("update_cab FREE ignored, cab_id={} is not on shift", cab_id);
+            return Some(CabStatus::OFFLINE);
+        }
+    };
+    // since the start of the shift or the end of the last break
+    let res: Result<Option<(Option<NaiveDateTime>, bool)>> = c.exec_first(
+        "SELECT MAX(ended), COUNT(*) > COUNT(ended) FROM shift_break WHERE shift_id=?",
+        (shift.id,),
+    );
+    let (last_break, on_break) = match res {
+        Ok(Some((ended, open))) => (ended.map(from_db), open),
+        _ => (None, false),
+    };
+    if on_break {
+        info!("update_cab FREE ignored, cab_id={} is on a break", cab_id);
+        return Some(CabStatus::ONBREAK);
+    }
+    let driving = get_elapsed(cmp::max(shift.started, last_break)) / 60;
+    if driving > unsafe { MAX_DRIVING } {
+        info!(
+            "update_cab FREE ignored, cab_id={} has been driving {} minutes without a break",
+            cab_id, driving
+        );
+        open_break(c, shift.id);
+        return Some(CabStatus::ONBREAK);
+    }
+    return None;
+}
+
+fn open_break(c: &mut PooledConn, shift_id: i64) {
+    check_result(c.exec_iter(
+        "INSERT INTO shift_break (shift_id, started) \
+        SELECT ?, ? FROM DUAL WHERE NOT EXISTS (SELECT 1 FROM shift_break WHERE shift_id=? AND ended IS NULL)",
+        (shift_id, Utc::now().naive_utc(), shift_id),
+    ));
+}
+
+fn select_open_shift(c: &mut PooledConn, cab_id: i64) -> Option<Shift> {
+    let res: Result<Option<(i64, NaiveDateTime)>> = c.exec_first(
+        "SELECT id, started FROM shift WHERE cab_id=? AND ended IS NULL ORDER BY started DESC LIMIT 1",
+        (cab_id,),
+    );
+    return match res {
+        Ok(Some((id, started))) => Some(Shift {
+            id,
+            cab_id,
+            started: Some(from_db(started)),
+            ended: None,
+            worked: 0,
+            breaks: 0,
+        }),
+        _ => None,
+    };
+}
+
+fn no_shift(cab_id: i64) -> Shift {
+    return Shift {
+        id: -1,
+        cab_id,
+        started: None,
+        ended: None,
+        worked: 0,
+        breaks: 0,
+    };
+}
+
+// an OFFLINE cab becomes FREE; starting a shift twice gives the open one
+pub fn start_shift(user_id: i64, c: &mut PooledConn, _id: i64) -> Shift {
+    debug!("start_shift, user_id={}", user_id);
+    // conditional, two requests at once must not open two shifts
+    let res = c.exec_drop(
+        "INSERT INTO shift (cab_id, started) \
+        SELECT ?, ? FROM DUAL WHERE NOT EXISTS (SELECT 1 FROM shift WHERE cab_id=? AND ended IS NULL)",
+        (user_id, Utc::now().naive_utc(), user_id),
+    );
+    if let Err(err) = res {
+        warn!("start_shift failed, cab_id={}: {}", user_id, err);
+        return no_shift(user_id);
+    }
+    check_result(c.exec_iter(
+        "UPDATE cab SET status=1 WHERE id=? AND status=3 AND active=true", // OFFLINE -> FREE
+        (user_id,),
+    ));
+    return select_open_shift(c, user_id).unwrap_or_else(|| no_shift(user_id));
+}
+
+// not possible with passengers on board or waiting, i.e. when ASSIGNED
+pub fn end_shift(user_id: i64, c: &mut PooledConn, _id: i64) -> Shift {
+    debug!("end_shift, user_id={}", user_id);
+    let shift = match select_open_shift(c, user_id) {
+        Some(s) => s,
+        None => return no_shift(user_id),
+    };
+    if select_cab(user_id, c, user_id).status == CabStatus::ASSIGNED {
+        info!("end_shift refused, cab_id={} is assigned", user_id);
+        return no_shift(user_id);
+    }
+    let now = Utc::now().naive_utc();
+    check_result(c.exec_iter(
+        "UPDATE shift_break SET ended=? WHERE shift_id=? AND ended IS NULL",
+        (now, shift.id),
+    ));
+    check_result(c.exec_iter("UPDATE shift SET ended=? WHERE id=?", (now, shift.id)));
+    check_result(c.exec_iter(
+        "UPDATE cab SET status=3 WHERE id=?", // 3=OFFLINE
+        (user_id,),
+    ));
+    return select_shifts_by_params(c, "s.id=?", vec![shift.id.into()])
+        .pop()
+        .unwrap_or(shift);
+}
+
+pub fn start_break(user_id: i64, c: &mut PooledConn, _id: i64) -> Shift {
+    debug!("start_break, user_id={}", user_id);
+    let shift = match select_open_shift(c, user_id) {
+        Some(s) => s,
+        None => return no_shift(user_id),
+    };
+    let current = select_cab(user_id, c, user_id).status;
+    if !current.can_start_break() {
+        info!("start_break refused, cab_id={} is {}", user_id, current);
+        return no_shift(user_id);
+    }
+    // the status could have changed in the meantime, e.g. Kern assigned the cab
+    let changed = check_result(c.exec_iter(
+        "UPDATE cab SET status=4 WHERE id=? AND status=1", // FREE -> ONBREAK
+        (user_id,),
+    ));
+    if changed == 0 {
+        info!(
+            "start_break refused, cab_id={} is not FREE any more",
+            user_id
+        );
+        return no_shift(user_id);
     }
-    return cab.clone();
+    open_break(c, shift.id);
+    return shift;
+}
+
+pub fn end_break(user_id: i64, c: &mut PooledConn, _id: i64) -> Shift {
+    debug!("end_break, user_id={}", user_id);
+    let shift = match select_open_shift(c, user_id) {
+        Some(s) => s,
+        None => return no_shift(user_id),
+    };
+    check_result(c.exec_iter(
+        "UPDATE shift_break SET ended=? WHERE shift_id=? AND ended IS NULL",
+        (Utc::now().naive_utc(), shift.id),
+    ));
+    check_result(c.exec_iter(
+        "UPDATE cab SET status=1 WHERE id=? AND status=4", // ONBREAK -> FREE
+        (user_id,),
+    ));
+    return shift;
+}
+
+pub fn select_shifts(user_id: i64, c: &mut PooledConn, cab_id: i64, q: ShiftQuery) -> ShiftReport {
+    debug!("select_shifts, user_id={}", user_id);
+    let to = q.to.unwrap_or_else(Utc::now);
+    let from = q.from.unwrap_or(to - chrono::Duration::days(7));
+    let shifts = select_shifts_by_params(
+        c,
+        "s.cab_id=? AND s.started>=? AND s.started<?",
+        vec![
+            cab_id.into(),
+            from.naive_utc().into(),
+            to.naive_utc().into(),
+        ],
+    );
+    return ShiftReport {
+        cab_id,
+        worked: shifts.iter().map(|s| s.worked).sum(),
+        breaks: shifts.iter().map(|s| s.breaks).sum(),
+        shifts,
+    };
+}
+
+// minutes, a shift or a break not ended yet counts until now
+fn select_shifts_by_params(c: &mut PooledConn, clause: &str, mut params: Vec<Value>) -> Vec<Shift> {
+    let now: Value = Utc::now().naive_utc().into();
+    params.insert(0, now.clone());
+    params.insert(0, now);
+    // id, cab_id, started, ended, length, breaks
+    type ShiftRow = (i64, i64, NaiveDateTime, Option<NaiveDateTime>, i64, i64);
+    let res: Result<Vec<ShiftRow>> = c.exec(
+        "SELECT s.id, s.cab_id, s.started, s.ended, TIMESTAMPDIFF(MINUTE, s.started, COALESCE(s.ended, ?)), \
+            COALESCE((SELECT SUM(TIMESTAMPDIFF(MINUTE, b.started, COALESCE(b.ended, ?))) FROM shift_break b WHERE b.shift_id=s.id), 0) \
+        FROM shift s WHERE "
+            .to_string()
+            + clause
+            + " ORDER BY s.started",
+        Params::Positional(params),
+    );
+    return match res {
+        Ok(rows) => rows
+            .into_iter()
+            .map(|(id, cab_id, started, ended, length, breaks)| Shift {
+                id,
+                cab_id,
+                started: Some(from_db(started)),
+                ended: ended.map(from_db),
+                worked: length - breaks,
+                breaks,
+            })
+            .collect(),
+        Err(err) => {
+            warn!("Shifts not read: {}", err);
+            Vec::new()
+        }
+    };
+}
+
+// ADMIN, fleet onboarding
+pub fn insert_cab(user_id: i64, c: &mut PooledConn, cab: CabInfo) -> CabInfo {
+    if cab.cab.id < 1 || cab.cab.seats < 1 || cab.cab.status == CabStatus::UNKNOWN {
+        warn!(
+            "insert_cab refused, user_id={}, cab_id={}, seats={}, status={}",
+            user_id, cab.cab.id, cab.cab.seats, cab.cab.status
+        );
+        return CabInfo {
+            cab: Cab {
+                ..Default::default()
+            },
+            name: None,
+            active: false,
+        };
+    }
+    let res = c.exec_drop(
+        "INSERT INTO cab (id, location, status, seats, name, active, features) VALUES ( \
+                :id, :location, :status, :seats, :name, :active, :features)",
+        params! {
+            "id" => cab.cab.id,
+            "location" => cab.cab.location,
+            "status" => cab.cab.status as i32,
+            "seats" => cab.cab.seats,
+            "name" => &cab.name,
+            "active" => cab.active,
+            "features" => cab.cab.features.to_mask()
+        },
+    );
+    return match res {
+        Ok(_) => {
+            info!("Cab registered, user_id={}, cab_id={}", user_id, cab.cab.id);
+            cab
+        }
+        Err(err) => {
+            warn!("insert_cab failed, cab_id={}, err: {}", cab.cab.id, err);
+            CabInfo {
+                cab: Cab {
+                    ..Default::default()
+                },
+                name: None,
+                active: false,
+            }
+        }
+    };
+}
+
+// seats, features, name and activity as far as sent; location and status belong to the cab itself
+pub fn update_cab_info(user_id: i64, c: &mut PooledConn, id: i64, cab: CabUpdate) -> CabInfo {
+    if cab.seats.is_some_and(|s| s < 1) {
+        warn!(
+            "update_cab_info refused, user_id={}, cab_id={}, seats={:?}",
+            user_id, id, cab.seats
+        );
+        return select_cab_info(user_id, c, id);
+    }
+    // NULL keeps the column as it is
+    check_result(c.exec_iter(
+        "UPDATE cab SET seats=COALESCE(?, seats), features=COALESCE(?, features), \
+        name=COALESCE(?, name), active=COALESCE(?, active) WHERE id=?",
+        (
+            cab.seats,
+            cab.features.map(|f| f.to_mask()),
+            &cab.name,
+            cab.active,
+            id,
+        ),
+    ));
+    if cab.active == Some(false) {
+        // it should not get new assignments
+        check_result(c.exec_iter(
+            "UPDATE cab SET status=? WHERE id=? AND status=?",
+            (CabStatus::OFFLINE as i32, id, CabStatus::FREE as i32),
+        ));
+    }
+    return select_cab_info(user_id, c, id);
+}
+
+pub fn deactivate_cab(user_id: i64, c: &mut PooledConn, id: i64) -> CabInfo {
+    let cab = select_cab_info(user_id, c, id);
+    if cab.cab.id == -1 {
+        return cab;
+    }
+    let deactivate = CabUpdate {
+        active: Some(false),
+        ..Default::default()
+    };
+    return update_cab_info(user_id, c, id, deactivate);
+}
+
+pub fn select_cab_info(user_id: i64, c: &mut PooledConn, id: i64) -> CabInfo {
+    debug!("select_cab_info, user_id={}", user_id);
+    let mut cabs = select_cabs_by_params(
+        c,
+        "SELECT id, location, status, seats, features, name, active FROM cab WHERE id=?"
+            .to_string(),
+        vec![id.into()],
+    );
+    return match cabs.pop() {
+        Some(cab) => cab,
+        None => CabInfo {
+            cab: Cab {
+                ..Default::default()
+            },
+            name: None,
+            active: false,
+        },
+    };
+}
+
+pub fn select_cabs(user_id: i64, c: &mut PooledConn, q: CabQuery) -> Vec<CabInfo> {
+    debug!("select_cabs, user_id={}", user_id);
+    let mut sql: String =
+        "SELECT id, location, status, seats, features, name, active FROM cab WHERE 1=1".to_string();
+    let mut params: Vec<Value> = vec![];
+    if let Some(status) = q.status {
+        sql += " AND status=?";
+        params.push((status as i32).into());
+    }
+    if let Some(location) = q.location {
+        sql += " AND location=?";
+        params.push(location.into());
+    }
+    if let Some(active) = q.active {
+        sql += " AND active=?";
+        params.push(active.into());
+    }
+    sql += " ORDER BY id";
+    return select_cabs_by_params(c, sql, params);
+}
+
+fn select_cabs_by_params(c: &mut PooledConn, sql: String, params: Vec<Value>) -> Vec<CabInfo> {
+    let params = if params.is_empty() {
+        Params::Empty
+    } else {
+        Params::Positional(params)
+    };
+    let res = c.exec_map(
+        sql,
+        params,
+        |(id, location, status, seats, features, name, active)| CabInfo {
+            cab: Cab {
+                id,
+                location,
+                status: get_cab_status(status),
+                seats,
+                features: Features::from_mask(features),
+            },
+            name,
+            active,
+        },
+    );
+    return match res {
+        Ok(rows) => rows,
+        Err(err) => {
+            warn!("Problem reading cabs: {:?}", err);
+            Vec::new()
+        }
+    };
 }
 
 pub fn assign_free_cab(user_id: i64, c: &mut PooledConn, o: CabAssign) -> bool {
@@ -77,15 +545,32 @@ pub fn assign_free_cab(user_id: i64, c: &mut PooledConn, o: CabAssign) -> bool {
         warn!("from == to, Kaut shouldn't allow this");
         return false;
     }
+    let cab = select_cab(user_id, c, user_id);
+    if o.passengers < 1 || o.passengers > cab.seats as i32 {
+        warn!(
+            "Wrong number of passengers for a free cab: cab_id={}, passengers={}, seats={}",
+            user_id, o.passengers, cab.seats
+        );
+        return false;
+    }
+    if !cab.features.covers(&o.requires) {
+        warn!(
+            "Free cab lacks features the customer needs: cab_id={}, requires={:?}",
+            user_id, o.requires
+        );
+        return false;
+    }
     let res = c.exec_drop(
-        "INSERT INTO freetaxi_order (from_stand, to_stand, shared, max_loss, cab_id, customer_id, received) VALUES ( \
-                :from_stand, :to_stand, :shared, :max_loss, :cab_id, :customer_id, :received)",
+        "INSERT INTO freetaxi_order (from_stand, to_stand, shared, max_loss, cab_id, customer_id, received, passengers, requires) VALUES ( \
+                :from_stand, :to_stand, :shared, :max_loss, :cab_id, :customer_id, :received, :passengers, :requires)",
         params! {
             "from_stand" => o.from,
             "to_stand" => o.to,
             "max_loss" => o.loss,
             "shared"   => o.shared,
-            "received" => Local::now().naive_local(),
+            "passengers" => o.passengers,
+            "requires" => o.requires.to_mask(),
+            "received" => Utc::now().naive_utc(),
             "cab_id"=> user_id,
             "customer_id" => o.cust_id
         },
@@ -99,17 +584,20 @@ pub fn assign_free_cab(user_id: i64, c: &mut PooledConn, o: CabAssign) -> bool {
 }
 
 pub fn assign_to_route(user_id: i64, c: &mut PooledConn, o: CabAssign) -> bool {
-    // if distance meets loss
     // if passengers met (seats), Kaut should checkit too
-    // check if leg not started
-    // find route_id (could be sent by cab) and leg_id (the same)
-    // create order
+    // 'from' and 'to' are added to the route as new stops if they are not there,
+    // as long as passengers already assigned are not taken too far out of their way
+    if o.from == o.to {
+        warn!("from == to, Kaut shouldn't allow this");
+        return false;
+    }
 
     let fake_cab: Cab = Cab {
         id: user_id,
         location: o.from,
         status: CabStatus::ASSIGNED,
         seats: -1,
+        features: Features::default(),
     };
     let o: Order = Order {
         id: -1,
@@ -117,11 +605,11 @@ pub fn assign_to_route(user_id: i64, c: &mut PooledConn, o: CabAssign) -> bool {
         to: o.to,
         wait: -1,
         loss: o.loss,
-        distance: unsafe { DIST[o.from as usize][o.to as usize] as i32 },
+        distance: get_dist(o.from, o.to) as i32,
         shared: o.shared,
         in_pool: true,
         status: OrderStatus::PICKEDUP,
-        received: Some(Local::now().naive_local()),
+        received: Some(Utc::now()),
         started: None,
         completed: None,
         at_time: None,
@@ -130,49 +618,240 @@ pub fn assign_to_route(user_id: i64, c: &mut PooledConn, o: CabAssign) -> bool {
         cust_id: o.cust_id,
         route_id: -1,
         leg_id: -1,
+        passengers: o.passengers,
+        est_fare: -1,
+        fare: -1,
+        requires: o.requires,
     };
-    let route = select_route_by_cab(user_id, c, user_id);
-    if !enough_place(&route.legs, o.from, o.to, route.cab.seats as i32, 1) {
-        // TODO: 1 -> Kaut should allow for co-passengers
+    if o.passengers < 1 {
         warn!(
-            "Not enough seats for route extension: user_id:{}, from: {}, to: {}",
-            user_id, o.from, o.to
+            "Wrong number of passengers for route extension: user_id:{}, passengers: {}",
+            user_id, o.passengers
         );
         return false;
     }
-    let leg_id = find_leg_at_stop(&route.legs, o.from);
-
-    let ord = insert_order(o.cust_id, c, o);
-    if ord.id == -1 {
+    let route = select_route_by_cab(user_id, c, user_id);
+    if route.legs.is_empty() {
+        warn!("No route to extend: user_id:{}", user_id);
         return false;
     }
-    let count = check_result(c.exec_iter(
-        "UPDATE taxi_order SET cab_id=?, route_id=?, leg_id=?, status=7, in_pool=true WHERE id=?", // 7=PICKEDUP
-        (user_id, route.id, leg_id, ord.id),
-    ));
-    if count == 1 {
-        update_avail_seats(c, route.id, &route.legs, o.from, o.to, 1);
-        return true;
-    } else {
+    if !route.cab.features.covers(&o.requires) {
+        warn!(
+            "Cab lacks features the customer needs: user_id:{}, requires: {:?}",
+            user_id, o.requires
+        );
+        return false;
+    }
+    let mut legs: Vec<Leg> = route.legs.clone();
+    let changed = add_stops(&mut legs, o.from, o.to);
+    if changed {
+        let orders: Vec<Order> = select_orders_by_route(user_id, c, route.id);
+        if !detours_acceptable(&route, &legs, &orders) {
+            warn!(
+                "Route extension too long for passengers: user_id:{}, route_id: {}, from: {}, to: {}",
+                user_id, route.id, o.from, o.to
+            );
+            return false;
+        }
+    }
+    if !enough_place(&legs, o.from, o.to, route.cab.seats as i32, o.passengers) {
         warn!(
-            "Update taxi_order went wrong, cab_id={}, route_id={}, leg_id={}, id={}",
-            user_id, route.id, leg_id, ord.id
+            "Not enough seats for route extension: user_id:{}, from: {}, to: {}, passengers: {}",
+            user_id, o.from, o.to, o.passengers
         );
         return false;
     }
+
+    if !order_acceptable(c, &o) {
+        return false;
+    }
+    let mut tx = match c.start_transaction(TxOpts::default()) {
+        Ok(tx) => tx,
+        Err(err) => {
+            warn!("assign_to_route, no transaction: {}", err);
+            return false;
+        }
+    };
+    // the new legs, the seats and the order together or nothing
+    let res: Result<bool> = (|| {
+        if changed {
+            save_legs(&mut tx, route.id, &mut legs)?;
+        }
+        if !take_seats(
+            &mut tx,
+            route.id,
+            &legs,
+            o.from,
+            o.to,
+            o.passengers,
+            route.cab.seats as i32,
+        )? {
+            warn!(
+                "Seats taken meanwhile, user_id:{}, route_id: {}, passengers: {}",
+                user_id, route.id, o.passengers
+            );
+            return Ok(false);
+        }
+        let ord = insert_order_row(&mut tx, &o)?;
+        tx.exec_drop(
+            "UPDATE taxi_order SET cab_id=?, route_id=?, leg_id=?, status=7, in_pool=true WHERE id=?", // 7=PICKEDUP
+            (user_id, route.id, find_leg_to_join(&legs, o.from), ord.id),
+        )?;
+        return Ok(tx.affected_rows() == 1);
+    })();
+    return commit_if(tx, res, "assign_to_route");
 }
 
-fn find_leg_at_stop(legs: &Vec<Leg>, stop: i32) -> i64 {
-    if legs.len() == 0 {
-        return -1;
+// Makes 'from' the start of a leg not started yet and 'to' the end of a later leg. A missing stop
+// splits the leg where it adds the least time, or is appended after the last leg.
+// New legs have id 0 until saved. False if the route already had both.
+fn add_stops(legs: &mut Vec<Leg>, from: i32, to: i32) -> bool {
+    let first_ahead = legs.iter().position(is_leg_ahead).unwrap_or(legs.len());
+    let mut changed = false;
+    let pos_from = match find_stop(legs, first_ahead, from) {
+        Some(p) => p,
+        None => {
+            changed = true;
+            add_stop(legs, first_ahead, from)
+        }
+    };
+    // 'to' cannot be the start of the leg 'from' starts
+    if find_stop(legs, pos_from + 1, to).is_none() {
+        changed = true;
+        add_stop(legs, pos_from, to);
     }
+    if changed {
+        for (place, l) in legs.iter_mut().enumerate() {
+            l.place = place as i32;
+        }
+    }
+    return changed;
+}
+
+// a stop is at position 'p' if legs[p] starts there, or at legs.len() if the last leg ends there
+fn find_stop(legs: &[Leg], start: usize, stop: i32) -> Option<usize> {
+    if let Some(p) = legs.iter().skip(start).position(|l| l.from == stop) {
+        return Some(start + p);
+    }
+    if start <= legs.len() && legs.last().is_some_and(|l| l.to == stop) {
+        return Some(legs.len());
+    }
+    return None;
+}
+
+// returns the position of the new stop
+fn add_stop(legs: &mut Vec<Leg>, start: usize, stop: i32) -> usize {
+    let last = legs[legs.len() - 1];
+    // appending costs the way there and the stop
+    let mut best_k = legs.len();
+    let mut best_added = get_dist(last.to, stop) as i32 + STOP_WAIT;
+    for (k, l) in legs.iter().enumerate().skip(start) {
+        let added =
+            get_dist(l.from, stop) as i32 + STOP_WAIT + get_dist(stop, l.to) as i32 - l.dist;
+        if added < best_added {
+            best_k = k;
+            best_added = added;
+        }
+    }
+    if best_k == legs.len() {
+        legs.push(Leg {
+            id: 0,
+            route_id: last.route_id,
+            from: last.to,
+            to: stop,
+            place: last.place + 1,
+            dist: get_dist(last.to, stop) as i32,
+            started: None,
+            completed: None,
+            status: RouteStatus::ASSIGNED,
+            passengers: 0,
+        });
+        return legs.len();
+    }
+    let split = legs[best_k];
+    legs[best_k].to = stop;
+    legs[best_k].dist = get_dist(split.from, stop) as i32;
+    legs.insert(
+        best_k + 1,
+        Leg {
+            id: 0,
+            from: stop,
+            dist: get_dist(stop, split.to) as i32,
+            started: None,
+            completed: None,
+            ..split
+        },
+    );
+    return best_k + 1;
+}
+
+// passengers assigned to the route and the ones in the cab keep within their max_loss,
+// the ones waiting are not picked up later than their max_wait unless it has been so before
+fn detours_acceptable(route: &Route, legs: &[Leg], orders: &[Order]) -> bool {
+    let extended = Route {
+        legs: legs.to_vec(),
+        ..route.clone()
+    };
+    for o in orders {
+        if o.status != OrderStatus::ASSIGNED
+            && o.status != OrderStatus::ACCEPTED
+            && o.status != OrderStatus::PICKEDUP
+        {
+            continue;
+        }
+        let before = ride_of_order(&route.legs, o);
+        let after = ride_of_order(legs, o);
+        if after > before && after * 100 > o.distance * (100 + o.loss) {
+            return false;
+        }
+        if o.status != OrderStatus::PICKEDUP {
+            let eta_before = calculate_eta(o.from, route) as i32;
+            let eta_after = calculate_eta(o.from, &extended) as i32;
+            if eta_after > eta_before && eta_after > o.wait {
+                return false;
+            }
+        }
+    }
+    return true;
+}
+
+// minutes from the pickup leg of the order to its destination, done legs included
+fn ride_of_order(legs: &[Leg], o: &Order) -> i32 {
+    let mut ride = 0;
+    let mut start_found = false;
     for l in legs {
-        if l.from == stop && l.status != RouteStatus::COMPLETED {
-            // just !completed ? maybe more specific
-            return l.id;
+        if !start_found && (l.id == o.leg_id || (o.leg_id <= 0 && l.from == o.from)) {
+            start_found = true;
+        }
+        if start_found {
+            ride += l.dist;
+            if l.to == o.to {
+                return ride;
+            }
+            ride += STOP_WAIT;
         }
     }
-    return -1;
+    return ride;
+}
+
+// legs split or added by 'add_stops', new ids from AUTO_INCREMENT are set in 'legs'
+fn save_legs(tx: &mut Transaction, route_id: i64, legs: &mut [Leg]) -> Result<()> {
+    for l in legs.iter_mut() {
+        if l.id == 0 {
+            tx.exec_drop(
+                "INSERT INTO leg (from_stand, to_stand, place, distance, status, route_id, passengers) \
+                VALUES (?, ?, ?, ?, ?, ?, ?)",
+                (l.from, l.to, l.place, l.dist, l.status as i32, route_id, l.passengers),
+            )?;
+            l.id = tx.last_insert_id().unwrap_or(0) as i64;
+        } else if is_leg_ahead(l) {
+            tx.exec_drop(
+                "UPDATE leg SET to_stand=?, distance=?, place=? WHERE id=? AND route_id=?",
+                (l.to, l.dist, l.place, l.id, route_id),
+            )?;
+        }
+    }
+    return Ok(());
 }
 
 fn enough_place(legs: &Vec<Leg>, from: i32, to: i32, seats: i32, place_needed: i32) -> bool {
@@ -181,8 +860,7 @@ fn enough_place(legs: &Vec<Leg>, from: i32, to: i32, seats: i32, place_needed: i
     }
     let mut start_found: bool = false;
     for l in legs {
-        if l.from == from && l.status != RouteStatus::COMPLETED && l.status != RouteStatus::STARTED
-        {
+        if l.from == from && is_leg_ahead(l) {
             start_found = true;
         }
         if start_found && l.passengers + place_needed > seats {
@@ -200,52 +878,282 @@ fn enough_place(legs: &Vec<Leg>, from: i32, to: i32, seats: i32, place_needed: i
     return true;
 }
 
-fn update_avail_seats(
-    c: &mut PooledConn,
+// places of the first and the last leg a passenger from 'from' to 'to' rides on
+fn leg_span(legs: &[Leg], from: i32, to: i32) -> Option<(i32, i32)> {
+    let mut start: Option<i32> = None;
+    for l in legs {
+        if start.is_none() && l.from == from && is_leg_ahead(l) {
+            start = Some(l.place);
+        }
+        if let Some(start) = start {
+            if l.to == to {
+                return Some((start, l.place));
+            }
+        }
+    }
+    // 'to' not found, to the end of the route
+    return start.map(|start| (start, i32::MAX));
+}
+
+// An assignment takes seats on the legs between 'from' and 'to', if every one of them still has
+// them. The update locks the legs, two orders cannot both get the last seat. False if the seats
+// are not there, the legs may be half updated then - 'tx' has to be rolled back.
+fn take_seats(
+    tx: &mut Transaction,
     route_id: i64,
-    legs: &Vec<Leg>,
+    legs: &[Leg],
     from: i32,
     to: i32,
-    place_needed: i32,
-) -> i32 {
-    let max: i32 = 2000;
-    if legs.len() == 0 {
-        return 0;
+    passengers: i32,
+    seats: i32,
+) -> Result<bool> {
+    let (start, stop) = match leg_span(legs, from, to) {
+        Some(span) => span,
+        None => return Ok(false),
+    };
+    let count = legs
+        .iter()
+        .filter(|l| l.place >= start && l.place <= stop)
+        .count() as u64;
+    tx.exec_drop(
+        "UPDATE leg SET passengers = passengers + ? WHERE route_id = ? AND place >= ? AND place <= ? \
+        AND passengers + ? <= ?",
+        (passengers, route_id, start, stop, passengers, seats),
+    )?;
+    return Ok(tx.affected_rows() == count);
+}
+
+// commits if 'res' is Ok(true), otherwise rolls back; false if nothing has changed
+fn commit_if(tx: Transaction, res: Result<bool>, what: &str) -> bool {
+    return match res {
+        Ok(true) => match tx.commit() {
+            Ok(_) => true,
+            Err(err) => {
+                warn!("{}, commit failed: {}", what, err);
+                query_failed();
+                false
+            }
+        },
+        Ok(false) => {
+            let _ = tx.rollback();
+            false
+        }
+        Err(err) => {
+            warn!("{} failed: {}", what, err);
+            query_failed();
+            let _ = tx.rollback();
+            false
+        }
+    };
+}
+
+// a cancellation gives the seats back, on the legs in 'legs' too
+fn free_seats(
+    tx: &mut Transaction,
+    route_id: i64,
+    legs: &mut [Leg],
+    from: i32,
+    to: i32,
+    passengers: i32,
+) -> Result<()> {
+    let (start, stop) = match leg_span(legs, from, to) {
+        Some(span) => span,
+        None => return Ok(()),
+    };
+    tx.exec_drop(
+        "UPDATE leg SET passengers = passengers - ? WHERE route_id = ? AND place >= ? AND place <= ?",
+        (passengers, route_id, start, stop),
+    )?;
+    for l in legs.iter_mut() {
+        if l.place >= start && l.place <= stop {
+            l.passengers -= passengers;
+        }
     }
-    let mut start_found: bool = false;
-    let mut start: i32 = 0;
-    let mut stop: i32 = max; // just big enough
-    for l in legs {
-        if l.from == from && l.status != RouteStatus::COMPLETED && l.status != RouteStatus::STARTED
-        {
-            start_found = true;
-            start = l.place;
+    return Ok(());
+}
+
+// Stops nobody gets on or off at anymore are taken out of the legs not started yet: two legs
+// meeting at such a stop become one, empty legs at the end go. Returns ids of the legs gone.
+fn drop_stops(legs: &mut Vec<Leg>, needed: &[i32]) -> Vec<i64> {
+    let mut gone: Vec<i64> = vec![];
+    let mut i = 0;
+    while i + 1 < legs.len() {
+        let (l, next) = (legs[i], legs[i + 1]);
+        if is_leg_ahead(&l) && is_leg_ahead(&next) && !needed.contains(&l.to) {
+            legs[i].to = next.to;
+            legs[i].dist = get_dist(l.from, next.to) as i32;
+            gone.push(next.id);
+            legs.remove(i + 1);
+        } else {
+            i += 1;
         }
-        if start_found && l.to == to {
-            stop = l.place;
+    }
+    while let Some(l) = legs.last() {
+        if l.passengers > 0 || !is_leg_ahead(l) {
             break;
         }
+        gone.push(l.id);
+        legs.pop();
     }
-    if !start_found {
-        return 0;
+    let first = legs.first().map(|l| l.place).unwrap_or(0);
+    for (k, l) in legs.iter_mut().enumerate() {
+        l.place = first + k as i32;
     }
-    check_result(c.exec_iter("UPDATE leg SET passengers = passengers - ? WHERE route_id = ? AND place  >= ? AND place <= ?", 
-                            (place_needed, route_id, start, stop)));
-    return stop - start + 1;
+    return gone;
+}
+
+// RECEIVED orders that nobody has assigned for 'older_than' seconds, the oldest first;
+// booked trips when their time has come
+pub fn select_orders_to_dispatch(c: &mut PooledConn, older_than: i64) -> Vec<Order> {
+    let now = Utc::now();
+    let sql = ORDER_SELECT.to_string()
+        + "o.status=0 AND o.received<? AND (o.at_time IS NULL OR o.at_time<=?) ORDER BY o.received";
+    return select_orders_by_params(
+        c,
+        sql,
+        Params::Positional(vec![
+            (now - chrono::Duration::seconds(older_than))
+                .naive_utc()
+                .into(),
+            now.naive_utc().into(),
+        ]),
+    );
+}
+
+pub fn select_free_cabs(c: &mut PooledConn) -> Vec<Cab> {
+    let res = c.exec_map(
+        "SELECT id, location, status, seats, features FROM cab WHERE status=1 AND active=true", // 1=FREE
+        (),
+        |(id, location, status, seats, features)| Cab {
+            id,
+            location,
+            status: get_cab_status(status),
+            seats,
+            features: Features::from_mask(features),
+        },
+    );
+    return res.unwrap_or_default();
+}
+
+// free cabs at most 'radius' minutes from 'stop', a quote does not need the whole fleet
+fn select_free_cabs_near(c: &mut PooledConn, stop: i32, radius: i16) -> Vec<Cab> {
+    // ids copied first, get_dist must not be called with STOPS locked
+    let ids: Vec<i64> = STOPS.read().unwrap().iter().map(|s| s.id).collect();
+    let near: Vec<Value> = ids
+        .into_iter()
+        .filter(|id| get_dist(*id as i32, stop) <= radius)
+        .map(|id| id.into())
+        .collect();
+    if near.is_empty() {
+        return vec![];
+    }
+    let res = c.exec_map(
+        format!(
+            "SELECT id, location, status, seats, features FROM cab WHERE status=1 AND active=true \
+            AND location IN ({})", // 1=FREE
+            vec!["?"; near.len()].join(",")
+        ),
+        Params::Positional(near),
+        |(id, location, status, seats, features)| Cab {
+            id,
+            location,
+            status: get_cab_status(status),
+            seats,
+            features: Features::from_mask(features),
+        },
+    );
+    return res.unwrap_or_default();
+}
+
+// A route for one order, as Kern would do it: to the customer if the cab is not there, then
+// to the destination. False if the cab or the order has been taken in the meantime.
+pub fn insert_route(c: &mut PooledConn, cab: &Cab, o: &Order) -> bool {
+    let mut tx = match c.start_transaction(TxOpts::default()) {
+        Ok(tx) => tx,
+        Err(err) => {
+            warn!("insert_route, no transaction: {}", err);
+            return false;
+        }
+    };
+    let res: Result<bool> = (|| {
+        tx.exec_drop(
+            "UPDATE cab SET status=0 WHERE id=? AND status=1 AND active=true", // 0=ASSIGNED
+            (cab.id,),
+        )?;
+        if tx.affected_rows() != 1 {
+            return Ok(false);
+        }
+        tx.exec_drop(
+            "INSERT INTO route (status, cab_id) VALUES (1, ?)", // 1=ASSIGNED
+            (cab.id,),
+        )?;
+        let route_id = tx.last_insert_id().unwrap_or(0) as i64;
+        let mut place = 0;
+        if cab.location != o.from {
+            tx.exec_drop(
+                "INSERT INTO leg (from_stand, to_stand, place, distance, status, route_id, passengers) \
+                VALUES (?, ?, ?, ?, 1, ?, 0)",
+                (cab.location, o.from, place, get_dist(cab.location, o.from), route_id),
+            )?;
+            place += 1;
+        }
+        tx.exec_drop(
+            "INSERT INTO leg (from_stand, to_stand, place, distance, status, route_id, passengers) \
+            VALUES (?, ?, ?, ?, 1, ?, ?)",
+            (o.from, o.to, place, get_dist(o.from, o.to), route_id, o.passengers),
+        )?;
+        let leg_id = tx.last_insert_id().unwrap_or(0) as i64;
+        tx.exec_drop(
+            "UPDATE taxi_order SET status=1, cab_id=?, route_id=?, leg_id=?, eta=?, in_pool=false \
+            WHERE id=? AND status=0", // 1=ASSIGNED
+            (
+                cab.id,
+                route_id,
+                leg_id,
+                get_dist(cab.location, o.from),
+                o.id,
+            ),
+        )?;
+        return Ok(tx.affected_rows() == 1);
+    })();
+    return match res {
+        Ok(true) => match tx.commit() {
+            Ok(_) => true,
+            Err(err) => {
+                warn!("insert_route, commit failed: {}", err);
+                query_failed();
+                false
+            }
+        },
+        Ok(false) => {
+            debug!("insert_route, cab_id={} or order_id={} taken", cab.id, o.id);
+            let _ = tx.rollback();
+            false
+        }
+        Err(err) => {
+            warn!(
+                "insert_route failed, cab_id={}, order_id={}: {}",
+                cab.id, o.id, err
+            );
+            query_failed();
+            let _ = tx.rollback();
+            false
+        }
+    };
 }
 
 pub fn update_leg(user_id: i64, c: &mut PooledConn, leg: Leg) -> Leg {
     // these strange looking updates should authorize access
     if leg.status == RouteStatus::STARTED {
         check_result(c.exec_iter("UPDATE leg l, route r SET l.status=?, l.started=? WHERE l.id=? AND r.id=l.route_id AND r.cab_id=?",
-                    (leg.status as i32, Local::now().naive_local(), leg.id, user_id)));
+                    (leg.status as i32, Utc::now().naive_utc(), leg.id, user_id)));
     } else if leg.status == RouteStatus::COMPLETED {
         debug!(
             "update_leg COMPLETED, user_id={} leg_id={}, status={}",
             user_id, leg.id, leg.status
         );
         check_result(c.exec_iter("UPDATE leg l, route r SET l.status=?, l.completed=? WHERE l.id=? AND r.id=l.route_id AND r.cab_id=?",
-                    (leg.status as i32, Local::now().naive_local(), leg.id, user_id)));
+                    (leg.status as i32, Utc::now().naive_utc(), leg.id, user_id)));
     } else {
         debug!(
             "update_leg with unknown status, user_id={} leg_id={}, status={}",
@@ -319,8 +1227,8 @@ pub fn select_route_ref(c: &mut PooledConn, id: i64) -> Route {
                     to: r.get(2).unwrap(),
                     place: r.get(3).unwrap(),
                     dist: r.get(4).unwrap(),
-                    started: get_naivedate(&r, 5),
-                    completed: get_naivedate(&r, 6),
+                    started: get_datetime(&r, 5),
+                    completed: get_datetime(&r, 6),
                     status: get_route_status(r.get(7).unwrap()),
                     passengers: r.get(8).unwrap(),
                 });
@@ -345,13 +1253,43 @@ pub fn select_route_with_orders(user_id: i64, c: &mut PooledConn, id: i64) -> Ro
 
 pub fn select_order(user_id: i64, c: &mut PooledConn, id: i64) -> Order {
     debug!("select_order, user_id={}", user_id);
-    let orders: Vec<Order> = select_orders_by_what(c, id, "o.id=?");
-    return orders[0];
+    let mut orders: Vec<Order> = select_orders_by_what(c, id, "o.id=?");
+    return match orders.pop() {
+        Some(o) => o,
+        None => Order {
+            ..Default::default()
+        },
+    };
 }
 
 pub fn select_orders(user_id: i64, c: &mut PooledConn, id: i64) -> Vec<Order> {
     debug!("select_orders, user_id={}", user_id);
-    return select_orders_by_what(c, id, "customer_id=? AND (o.status<3 OR o.status>6)");
+    let orders = select_orders_by_what(c, id, "customer_id=? AND (o.status<3 OR o.status>6)");
+    // bookings for later are listed by select_upcoming_orders
+    return orders
+        .into_iter()
+        .filter(|o| !is_scheduled(o.at_time))
+        .collect();
+}
+
+pub fn select_upcoming_orders(user_id: i64, c: &mut PooledConn, id: i64) -> Vec<Order> {
+    debug!("select_upcoming_orders, user_id={}", user_id);
+    // whatever Kern has done with them already
+    let mut orders: Vec<Order> =
+        select_orders_by_what(c, id, "customer_id=? AND (o.status<3 OR o.status>6)")
+            .into_iter()
+            .filter(|o| is_scheduled(o.at_time))
+            .collect();
+    orders.sort_by_key(|o| o.at_time); // the nearest booking first
+    return orders;
+}
+
+// true if the order has been booked for later, not for now
+pub fn is_scheduled(at_time: Option<DateTime<Utc>>) -> bool {
+    return match at_time {
+        Some(t) => t > Utc::now(),
+        None => false,
+    };
 }
 
 pub fn select_orders_by_route(user_id: i64, c: &mut PooledConn, id: i64) -> Vec<Order> {
@@ -359,14 +1297,14 @@ pub fn select_orders_by_route(user_id: i64, c: &mut PooledConn, id: i64) -> Vec<
     return select_orders_by_what(c, id, "route_id=? AND (o.status<3 OR o.status>6)");
 }
 
-fn get_naivedate(row: &Row, index: usize) -> Option<NaiveDateTime> {
+fn get_datetime(row: &Row, index: usize) -> Option<DateTime<Utc>> {
     let val: Option<mysql::Value> = row.get(index);
     return match val {
         Some(x) => {
             if x == Value::NULL {
                 None
             } else {
-                row.get(index)
+                row.get::<NaiveDateTime, usize>(index).map(from_db)
             }
         }
         None => None,
@@ -387,53 +1325,23 @@ fn get_i64(row: &Row, index: usize) -> i64 {
     };
 }
 
+const ORDER_SELECT: &str = "SELECT from_stand, to_stand, max_wait, max_loss, distance, shared, in_pool, received, started, completed, \
+        at_time, eta, o.status, cab_id, customer_id, o.id, c.location, c.status, route_id, leg_id, c.seats, o.passengers, o.est_fare, o.fare, \
+        c.features, o.requires \
+        FROM taxi_order as o LEFT JOIN cab as c ON o.cab_id = c.id WHERE ";
+
 pub fn select_orders_by_what(c: &mut PooledConn, id: i64, clause: &str) -> Vec<Order> {
-    let sql = "SELECT from_stand, to_stand, max_wait, max_loss, distance, shared, in_pool, received, started, completed, \
-        at_time, eta, o.status, cab_id, customer_id, o.id, c.location, c.status, route_id, leg_id, c.seats \
-        FROM taxi_order as o LEFT JOIN cab as c ON o.cab_id = c.id WHERE ".to_string() 
-        + clause + " ORDER BY received desc"; // AND (o.status<3 OR o.status>6)
+    let sql = ORDER_SELECT.to_string() + clause + " ORDER BY received desc"; // AND (o.status<3 OR o.status>6)
+    return select_orders_by_params(c, sql, Params::Positional(vec![id.into()]));
+}
+
+fn select_orders_by_params(c: &mut PooledConn, sql: String, params: Params) -> Vec<Order> {
     let mut ret: Vec<Order> = Vec::new();
-    let selected: Result<Vec<Row>> = c.exec(sql, (id,));
+    let selected: Result<Vec<Row>> = c.exec(sql, params);
     match selected {
         Ok(sel) => {
             for r in sel {
-                let cab_id: Option<i64> = r.get(13).unwrap();
-                ret.push(Order {
-                    id: r.get(15).unwrap(),
-                    from: r.get(0).unwrap(),
-                    to: r.get(1).unwrap(),
-                    wait: r.get(2).unwrap(),
-                    loss: r.get(3).unwrap(),
-                    distance: r.get(4).unwrap(),
-                    shared: r.get(5).unwrap(),
-                    in_pool: r.get(6).unwrap(),
-                    received: get_naivedate(&r, 7),
-                    started: get_naivedate(&r, 8),
-                    completed: get_naivedate(&r, 9),
-                    at_time: get_naivedate(&r, 10),
-                    eta: r.get(11).unwrap(),
-                    status: get_order_status(r.get(12).unwrap()),
-                    cab: match cab_id {
-                        Some(cab_id) => Cab {
-                            id: cab_id,
-                            location: r.get(16).unwrap(),
-                            status: get_cab_status(r.get(17).unwrap()),
-                            seats: r.get(20).unwrap(),
-                        },
-                        None => {
-                            // not assigned
-                            Cab {
-                                id: -1,
-                                location: -1,
-                                status: CabStatus::CHARGING,
-                                seats: -1,
-                            }
-                        }
-                    },
-                    cust_id: r.get(14).unwrap(),
-                    route_id: get_i64(&r, 18),
-                    leg_id: get_i64(&r, 19),
-                });
+                ret.push(get_order_from_row(&r));
             }
         }
         Err(error) => warn!("Problem reading row: {:?}", error),
@@ -441,70 +1349,635 @@ pub fn select_orders_by_what(c: &mut PooledConn, id: i64, clause: &str) -> Vec<O
     return ret;
 }
 
+fn get_order_from_row(r: &Row) -> Order {
+    let cab_id: Option<i64> = r.get(13).unwrap();
+    return Order {
+        id: r.get(15).unwrap(),
+        from: r.get(0).unwrap(),
+        to: r.get(1).unwrap(),
+        wait: r.get(2).unwrap(),
+        loss: r.get(3).unwrap(),
+        distance: r.get(4).unwrap(),
+        shared: r.get(5).unwrap(),
+        in_pool: r.get(6).unwrap(),
+        received: get_datetime(r, 7),
+        started: get_datetime(r, 8),
+        completed: get_datetime(r, 9),
+        at_time: get_datetime(r, 10),
+        eta: r.get(11).unwrap(),
+        status: get_order_status(r.get(12).unwrap()),
+        cab: match cab_id {
+            Some(cab_id) => Cab {
+                id: cab_id,
+                location: r.get(16).unwrap(),
+                status: get_cab_status(r.get(17).unwrap()),
+                seats: r.get(20).unwrap(),
+                features: Features::from_mask(r.get(24).unwrap()),
+            },
+            None => {
+                // not assigned
+                Cab {
+                    id: -1,
+                    location: -1,
+                    status: CabStatus::UNKNOWN,
+                    seats: -1,
+                    features: Features::default(),
+                }
+            }
+        },
+        cust_id: r.get(14).unwrap(),
+        route_id: get_i64(r, 18),
+        leg_id: get_i64(r, 19),
+        passengers: r.get(21).unwrap(),
+        est_fare: get_i64(r, 22),
+        fare: get_i64(r, 23),
+        requires: Features::from_mask(r.get(25).unwrap()),
+    };
+}
+
+// past trips of a customer (owner is "o.customer_id") or of a cab ("o.cab_id"),
+// the filter is built of placeholders only, values go as parameters
+fn select_order_history(
+    c: &mut PooledConn,
+    owner: &str,
+    id: i64,
+    default_status: &str,
+    q: HistoryQuery,
+) -> OrderHistory {
+    let mut clause: String = owner.to_string() + "=?";
+    let mut params: Vec<Value> = vec![id.into()];
+    if let Some(from) = q.from {
+        clause += " AND o.received>=?";
+        params.push(from.naive_utc().into());
+    }
+    if let Some(to) = q.to {
+        clause += " AND o.received<?";
+        params.push(to.naive_utc().into());
+    }
+    match q.status {
+        Some(status) => {
+            clause += " AND o.status=?";
+            params.push((status as i32).into());
+        }
+        None => clause += default_status,
+    }
+    let size: i64 = q
+        .size
+        .unwrap_or(HISTORY_PAGE_SIZE)
+        .clamp(1, HISTORY_MAX_PAGE_SIZE);
+    let page: i64 = cmp::max(q.page.unwrap_or(1), 1);
+
+    let count_sql = "SELECT COUNT(*) FROM taxi_order as o WHERE ".to_string() + &clause;
+    let total: i64 = match c.exec_first(count_sql, Params::Positional(params.clone())) {
+        Ok(Some(count)) => count,
+        Ok(None) => 0,
+        Err(err) => {
+            warn!("Problem counting orders: {:?}", err);
+            0
+        }
+    };
+    params.push(size.into());
+    params.push(((page - 1) * size).into());
+    let sql = ORDER_SELECT.to_string() + &clause + " ORDER BY received desc LIMIT ? OFFSET ?";
+    let orders = select_orders_by_params(c, sql, Params::Positional(params));
+    return OrderHistory {
+        total,
+        page,
+        size,
+        orders,
+    };
+}
+
+pub fn select_customer_history(
+    user_id: i64,
+    c: &mut PooledConn,
+    id: i64,
+    q: HistoryQuery,
+) -> OrderHistory {
+    debug!("select_customer_history, user_id={}", user_id);
+    // trips that are over, one way or another
+    return select_order_history(c, "o.customer_id", id, " AND o.status IN (3,4,5,6,8)", q);
+}
+
+pub fn select_cab_history(
+    user_id: i64,
+    c: &mut PooledConn,
+    id: i64,
+    q: HistoryQuery,
+) -> OrderHistory {
+    debug!("select_cab_history, user_id={}", user_id);
+    return select_order_history(c, "o.cab_id", id, " AND o.status=8", q); // 8=COMPLETED
+}
+
 pub fn update_order(user_id: i64, c: &mut PooledConn, order: Order) -> Order {
+    // 'status<>?' - a repeated request does not count twice
     if order.status == OrderStatus::PICKEDUP {
-        check_result(c.exec_iter(
-            "UPDATE taxi_order SET status=?, started=? WHERE id=? AND customer_id=?",
+        let updated = check_result(c.exec_iter(
+            "UPDATE taxi_order SET status=?, started=? WHERE id=? AND customer_id=? AND status<>?",
             (
                 order.status as i32,
-                Local::now().naive_local(),
+                Utc::now().naive_utc(),
                 order.id,
                 user_id,
+                order.status as i32,
             ),
         ));
-        add_avg_pickup(get_elapsed_dt(order.received));
+        if updated > 0 {
+            count_pickup_kpis(c, order.id);
+        }
     } else if order.status == OrderStatus::COMPLETED {
-        check_result(c.exec_iter(
-            "UPDATE taxi_order SET status=?, completed=? WHERE id=? AND customer_id=?",
+        let updated = check_result(c.exec_iter(
+            "UPDATE taxi_order SET status=?, completed=? WHERE id=? AND customer_id=? AND status<>?",
             (
                 order.status as i32,
-                Local::now().naive_local(),
+                Utc::now().naive_utc(),
                 order.id,
                 user_id,
+                order.status as i32,
             ),
         ));
-        add_avg_complete(get_elapsed_dt(order.received));
+        if updated > 0 {
+            count_complete_kpis(c, order.id);
+            let mut ret = order;
+            ret.fare = settle_fare(c, order.id);
+            return ret;
+        }
     } else {
         check_result(c.exec_iter(
             "UPDATE taxi_order SET status=? WHERE id=? AND customer_id=?",
             (order.status as i32, order.id, user_id),
         ));
     }
-    return order.clone();
+    return order.clone();
+}
+
+// KPIs are counted from the columns in the DB, the client might send anything
+fn count_pickup_kpis(c: &mut PooledConn, order_id: i64) {
+    // a scheduled trip is not late before its time
+    let res: Result<Option<(i64, i64, i32)>> = c.exec_first(
+        "SELECT TIMESTAMPDIFF(SECOND, received, started), \
+            TIMESTAMPDIFF(SECOND, GREATEST(received, COALESCE(at_time, received)), started), max_wait \
+        FROM taxi_order WHERE id=? AND started IS NOT NULL AND received IS NOT NULL",
+        (order_id,),
+    );
+    match res {
+        Ok(Some((pickup, wait, max_wait))) => {
+            add_avg_pickup(pickup);
+            add_avg_wait(wait);
+            if max_wait > 0 {
+                add_avg_wait_of_max(wait * 100 / (max_wait as i64 * 60));
+            }
+        }
+        Ok(None) => warn!("No times to count KPIs, order_id={}", order_id),
+        Err(err) => warn!("KPIs not counted, order_id={}, err: {}", order_id, err),
+    }
+}
+
+fn count_complete_kpis(c: &mut PooledConn, order_id: i64) {
+    // complete, ride, from, to, max_loss, in_pool
+    type Times = (i64, Option<i64>, i32, i32, i32, bool);
+    let res: Result<Option<Times>> = c.exec_first(
+        "SELECT TIMESTAMPDIFF(SECOND, received, completed), TIMESTAMPDIFF(SECOND, started, completed), \
+            from_stand, to_stand, max_loss, in_pool \
+        FROM taxi_order WHERE id=? AND completed IS NOT NULL AND received IS NOT NULL",
+        (order_id,),
+    );
+    match res {
+        Ok(Some((complete, ride, from, to, max_loss, in_pool))) => {
+            add_avg_complete(complete);
+            add_completed_trip(in_pool);
+            let direct = get_dist(from, to) as i64 * 60;
+            if let (Some(ride), true) = (ride, direct > 0) {
+                let detour = (ride - direct) * 100 / direct;
+                add_avg_detour(detour);
+                if max_loss > 0 {
+                    add_avg_detour_of_loss(detour * 100 / max_loss as i64);
+                }
+            }
+        }
+        Ok(None) => warn!("No times to count KPIs, order_id={}", order_id),
+        Err(err) => warn!("KPIs not counted, order_id={}, err: {}", order_id, err),
+    }
+}
+
+// the fare for the direct trip, with the discount for the detour taken if shared
+fn settle_fare(c: &mut PooledConn, order_id: i64) -> i64 {
+    // from, to, distance, shared, ride, started
+    type Trip = (
+        i32,
+        i32,
+        Option<i32>,
+        bool,
+        Option<i64>,
+        Option<NaiveDateTime>,
+    );
+    let res: Result<Option<Trip>> = c.exec_first(
+        "SELECT from_stand, to_stand, distance, shared, TIMESTAMPDIFF(SECOND, started, completed), started \
+        FROM taxi_order WHERE id=?",
+        (order_id,),
+    );
+    let (from, to, distance, shared, ride, started) = match res {
+        Ok(Some(trip)) => trip,
+        Ok(None) => return -1,
+        Err(err) => {
+            warn!("Fare not counted, order_id={}, err: {}", order_id, err);
+            return -1;
+        }
+    };
+    // the distance stored with the order, the one estimated; DIST may have been reloaded since
+    let minutes = match distance {
+        Some(d) if d > 0 => d,
+        _ => get_dist(from, to) as i32,
+    };
+    let direct = minutes as i64 * 60;
+    let detour = match ride {
+        Some(ride) if direct > 0 => (ride - direct) * 100 / direct,
+        _ => 0,
+    };
+    let fare = final_fare(
+        minutes,
+        shared,
+        detour,
+        started.map(from_db).unwrap_or_else(Utc::now),
+    );
+    check_result(c.exec_iter("UPDATE taxi_order SET fare=? WHERE id=?", (fare, order_id)));
+    return fare;
+}
+
+// a customer changes their mind; what has to be undone depends on how far the order has gone
+pub fn cancel_order(user_id: i64, c: &mut PooledConn, cancel: OrderCancel) -> Order {
+    let orders = select_orders_by_what(c, cancel.id, "o.id=?");
+    if orders.is_empty() || orders[0].cust_id != user_id {
+        info!(
+            "cancel_order not found or not authorised, user_id={}, order_id={}",
+            user_id, cancel.id
+        );
+        return Order {
+            ..Default::default()
+        };
+    }
+    let order = orders[0];
+    match order.status {
+        OrderStatus::RECEIVED => {
+            // Kern has not found a cab yet, nothing to undo
+            check_result(c.exec_iter(
+                "UPDATE taxi_order SET status=?, cancel_reason=? WHERE id=? AND status=?",
+                (
+                    OrderStatus::CANCELLED as i32,
+                    &cancel.reason,
+                    order.id,
+                    OrderStatus::RECEIVED as i32,
+                ),
+            ));
+        }
+        OrderStatus::ASSIGNED | OrderStatus::ACCEPTED => {
+            cancel_assigned(c, &order, &cancel.reason);
+        }
+        _ => {
+            // the customer is in the cab or the trip is over
+            info!(
+                "cancel_order refused, order_id={}, status={}",
+                order.id, order.status
+            );
+        }
+    }
+    return select_order(user_id, c, order.id);
+}
+
+// the order, the seats and the stops of the cancelled customer go together or nothing
+fn cancel_assigned(c: &mut PooledConn, order: &Order, reason: &Option<String>) {
+    let on_route = order.route_id != -1;
+    let (route, others) = if on_route {
+        (
+            select_route_ref(c, order.route_id),
+            select_orders_by_route(order.cust_id, c, order.route_id),
+        )
+    } else {
+        (Route::default(), vec![])
+    };
+    let mut tx = match c.start_transaction(TxOpts::default()) {
+        Ok(tx) => tx,
+        Err(err) => {
+            warn!("cancel_order, no transaction: {}", err);
+            return;
+        }
+    };
+    let mut abandoned = false;
+    let res: Result<bool> = (|| {
+        tx.exec_drop(
+            "UPDATE taxi_order SET status=?, cancel_reason=?, route_id=NULL, leg_id=NULL, cab_id=NULL \
+                    WHERE id=? AND status IN (1,2)",
+            (OrderStatus::CANCELLED as i32, reason, order.id),
+        )?;
+        if tx.affected_rows() != 1 {
+            return Ok(false);
+        }
+        if on_route {
+            abandoned = detach_from_route(&mut tx, order, &route, &others)?;
+        }
+        return Ok(true);
+    })();
+    if !commit_if(tx, res, "cancel_order") || !on_route {
+        return;
+    }
+    if abandoned {
+        notify_cab(
+            route.cab.id,
+            format!("Order {} cancelled, route {} abandoned", order.id, route.id),
+        );
+    } else {
+        notify_cab(
+            route.cab.id,
+            format!(
+                "Order {} cancelled, route {} changed: pick-up at {} dropped",
+                order.id, route.id, order.from
+            ),
+        );
+    }
+}
+
+// true if the whole route was for this customer and has been abandoned
+fn detach_from_route(
+    tx: &mut Transaction,
+    order: &Order,
+    route: &Route,
+    others: &[Order],
+) -> Result<bool> {
+    let mut legs: Vec<Leg> = route.legs.clone();
+    free_seats(
+        tx,
+        route.id,
+        &mut legs,
+        order.from,
+        order.to,
+        order.passengers,
+    )?;
+    let needed: Vec<i32> = others
+        .iter()
+        .filter(|o| o.id != order.id)
+        .flat_map(|o| [o.from, o.to])
+        .collect();
+    let gone = drop_stops(&mut legs, &needed);
+    for id in &gone {
+        tx.exec_drop("DELETE FROM leg WHERE id=?", (id,))?;
+    }
+    for l in legs.iter().filter(|l| is_leg_ahead(l)) {
+        tx.exec_drop(
+            "UPDATE leg SET to_stand=?, distance=?, place=? WHERE id=?",
+            (l.to, l.dist, l.place, l.id),
+        )?;
+    }
+    let cab_id = route.cab.id;
+    let abandoned = legs.is_empty();
+    if abandoned {
+        tx.exec_drop(
+            "UPDATE route SET status=? WHERE id=?",
+            (RouteStatus::ABANDONED as i32, route.id),
+        )?;
+        tx.exec_drop(
+            "UPDATE cab SET status=? WHERE id=? AND status=?",
+            (CabStatus::FREE as i32, cab_id, CabStatus::ASSIGNED as i32),
+        )?;
+    }
+    info!(
+        "Order detached from route, order_id={}, route_id={}, cab_id={}, legs dropped={}",
+        order.id,
+        route.id,
+        cab_id,
+        gone.len()
+    );
+    return Ok(abandoned);
+}
+
+// POST /orders - a shared trip for now is offered a seat on a route that passes by
+pub fn insert_and_match_order(user_id: i64, c: &mut PooledConn, o: Order) -> Order {
+    let ord = insert_order(user_id, c, o);
+    if ord.id == -1 || !ord.shared || ord.at_time.is_some() || !unsafe { POOL_MATCHING } {
+        return ord;
+    }
+    return match_to_route(c, ord);
+}
+
+// Routes which stop at 'from' on a leg not started yet and then at 'to'. New legs are not added,
+// so passengers already on the route wait and ride as long as before. Every order on the route
+// must be shared, the cab must have seats on all legs between and the new customer's
+// max_wait and max_loss must be met. The route with the nearest pickup wins.
+fn find_route_to_join(c: &mut PooledConn, o: &Order) -> Option<(Route, i16)> {
+    let candidates: Result<Vec<i64>> = c.exec(
+        "SELECT DISTINCT l.route_id FROM leg l JOIN route r ON r.id=l.route_id \
+        WHERE l.from_stand=? AND l.status IN (0,1,2) AND r.status IN (1,2,5) \
+        AND NOT EXISTS (SELECT 1 FROM taxi_order t WHERE t.route_id=r.id AND t.shared=false AND t.status IN (1,2,7))",
+        (o.from,),
+    );
+    let candidates = match candidates {
+        Ok(ids) => ids,
+        Err(err) => {
+            warn!("find_route_to_join, routes not read: {}", err);
+            return None;
+        }
+    };
+    let mut best: Option<(Route, i16)> = None;
+    for id in candidates {
+        let route = select_route_ref(c, id);
+        if route.cab.id == -1
+            || !route.cab.features.covers(&o.requires)
+            || !enough_place(
+                &route.legs,
+                o.from,
+                o.to,
+                route.cab.seats as i32,
+                o.passengers,
+            )
+        {
+            continue;
+        }
+        let ride = ride_on_route(&route.legs, o.from, o.to);
+        if ride == -1 || ride * 100 > o.distance * (100 + o.loss) {
+            continue;
+        }
+        // a route can pass the stop twice, the visit ahead counts
+        let eta = eta_to_leg(&route.legs, find_leg_to_join(&route.legs, o.from));
+        if eta as i32 > o.wait {
+            continue;
+        }
+        if best.as_ref().is_none_or(|(_, best_eta)| eta < *best_eta) {
+            best = Some((route, eta));
+        }
+    }
+    return best;
+}
+
+fn match_to_route(c: &mut PooledConn, o: Order) -> Order {
+    let (route, eta) = match find_route_to_join(c, &o) {
+        Some(b) => b,
+        None => return o,
+    };
+    let leg_id = find_leg_to_join(&route.legs, o.from);
+    let mut tx = match c.start_transaction(TxOpts::default()) {
+        Ok(tx) => tx,
+        Err(err) => {
+            warn!("match_to_route, no transaction: {}", err);
+            return o;
+        }
+    };
+    // 'find_route_to_join' has read the seats before, another order may have taken them since
+    let res: Result<bool> = (|| {
+        if !take_seats(
+            &mut tx,
+            route.id,
+            &route.legs,
+            o.from,
+            o.to,
+            o.passengers,
+            route.cab.seats as i32,
+        )? {
+            return Ok(false);
+        }
+        tx.exec_drop(
+            "UPDATE taxi_order SET status=1, cab_id=?, route_id=?, leg_id=?, eta=?, in_pool=true WHERE id=? AND status=0", // 1=ASSIGNED
+            (route.cab.id, route.id, leg_id, eta, o.id),
+        )?;
+        return Ok(tx.affected_rows() == 1);
+    })();
+    if !commit_if(tx, res, "match_to_route") {
+        return o;
+    }
+    info!(
+        "Order matched to a route, order_id={}, route_id={}, cab_id={}, eta={}",
+        o.id, route.id, route.cab.id, eta
+    );
+    let mut ret = o;
+    ret.status = OrderStatus::ASSIGNED;
+    ret.cab = route.cab;
+    ret.route_id = route.id;
+    ret.leg_id = leg_id;
+    ret.eta = eta as i32;
+    ret.in_pool = true;
+    return ret;
+}
+
+// minutes from 'from' to 'to' along the legs with stops between, -1 if the route does not go there
+fn ride_on_route(legs: &[Leg], from: i32, to: i32) -> i32 {
+    let mut ride = 0;
+    let mut start_found = false;
+    for l in legs {
+        if !start_found && l.from == from && is_leg_ahead(l) {
+            start_found = true;
+        }
+        if start_found {
+            ride += l.dist;
+            if l.to == to {
+                return ride;
+            }
+            ride += STOP_WAIT;
+        }
+    }
+    return -1;
+}
+
+// the same leg as in 'enough_place' and 'leg_span'
+fn find_leg_to_join(legs: &[Leg], from: i32) -> i64 {
+    for l in legs {
+        if l.from == from && is_leg_ahead(l) {
+            return l.id;
+        }
+    }
+    return -1;
+}
+
+fn is_leg_ahead(l: &Leg) -> bool {
+    return l.status != RouteStatus::COMPLETED && l.status != RouteStatus::STARTED;
 }
 
 pub fn insert_order(user_id: i64, c: &mut PooledConn, o: Order) -> Order {
-    if o.from == o.to {
-        println!("a joker");
-        return Order {
-            ..Default::default()
-        };
-    } else if o.cust_id != user_id {
+    if o.cust_id != user_id {
         println!("a hacker");
         return Order {
             ..Default::default()
         };
-    }
-    let orders = select_orders_by_what(
-        c,
-        o.cust_id,
-        "customer_id=? AND (o.status<3 OR o.status = 7)",
-    );
-    if orders.len() > 0 {
-        println!("POST order failed for usr_id={}, orders exist", o.cust_id);
+    } else if !order_acceptable(c, &o) {
         return Order {
             ..Default::default()
         };
     }
-    let dist: i32;
-    unsafe {
-        dist = DIST[o.from as usize][o.to as usize] as i32;
+    return match insert_order_row(c, &o) {
+        Ok(ret) => ret,
+        Err(err) => {
+            println!("{}", err);
+            Order {
+                ..Default::default()
+            }
+        }
+    };
+}
+
+fn order_acceptable(c: &mut PooledConn, o: &Order) -> bool {
+    if o.from == o.to {
+        println!("a joker");
+        return false;
+    } else if get_stop(o.from as i64).is_none() || get_stop(o.to as i64).is_none() {
+        warn!(
+            "POST order failed for usr_id={}, unknown or retired stop from={} to={}",
+            o.cust_id, o.from, o.to
+        );
+        return false;
+    } else if o.passengers < 1 || o.passengers > select_max_seats(c) {
+        warn!(
+            "POST order failed for usr_id={}, no cab can take passengers={}",
+            o.cust_id, o.passengers
+        );
+        return false;
+    } else if !fleet_covers(c, &o.requires) {
+        warn!(
+            "POST order failed for usr_id={}, no cab has requires={:?}",
+            o.cust_id, o.requires
+        );
+        return false;
+    }
+    if let Some(at_time) = o.at_time {
+        let now = Utc::now();
+        if at_time <= now {
+            warn!(
+                "POST order failed for usr_id={}, at_time={} is not in the future",
+                o.cust_id, at_time
+            );
+            return false;
+        }
+        if (at_time - now).num_minutes() > unsafe { BOOKING_HORIZON } {
+            warn!(
+                "POST order failed for usr_id={}, at_time={} is beyond the booking horizon",
+                o.cust_id, at_time
+            );
+            return false;
+        }
+    } else {
+        // a customer can book many trips for later, but can have only one trip now
+        let orders: Vec<Order> = select_orders_by_what(
+            c,
+            o.cust_id,
+            "customer_id=? AND (o.status<3 OR o.status = 7)",
+        )
+        .into_iter()
+        .filter(|x| !is_scheduled(x.at_time))
+        .collect();
+        if !orders.is_empty() {
+            println!("POST order failed for usr_id={}, orders exist", o.cust_id);
+            return false;
+        }
     }
+    return true;
+}
 
-    let res = c.exec_drop(
+// RECEIVED, also within a transaction
+fn insert_order_row<Q: Queryable>(q: &mut Q, o: &Order) -> Result<Order> {
+    let dist: i32 = get_dist(o.from, o.to) as i32;
+    let received = Utc::now();
+    let est_fare = estimate_fare(dist, o.shared, o.at_time.unwrap_or(received));
+
+    q.exec_drop(
         "INSERT INTO taxi_order (from_stand, to_stand, max_loss, max_wait, shared, in_pool, eta,\
-                status, received, distance, customer_id) VALUES ( \
-                :from_stand, :to_stand, :max_loss, :max_wait, :shared, false, -1, :status, :received, :distance, :customer_id)",
+                status, received, distance, customer_id, at_time, passengers, est_fare, requires) VALUES ( \
+                :from_stand, :to_stand, :max_loss, :max_wait, :shared, false, -1, :status, :received, :distance, :customer_id, :at_time, :passengers, :est_fare, :requires)",
         params! {
             "from_stand" => o.from,
             "to_stand" => o.to,
@@ -512,28 +1985,23 @@ pub fn insert_order(user_id: i64, c: &mut PooledConn, o: Order) -> Order {
             "max_wait" => o.wait,
             "shared"   => o.shared,
             "status"   => OrderStatus::RECEIVED as i32,
-            "received" => Local::now().naive_local(),
+            "received" => received.naive_utc(),
             "distance" => dist,
-            "customer_id" => o.cust_id
+            "customer_id" => o.cust_id,
+            "at_time" => o.at_time.map(|t| t.naive_utc()),
+            "passengers" => o.passengers,
+            "est_fare" => est_fare,
+            "requires" => o.requires.to_mask()
         },
-    )
-    .and_then(|_| Ok(c.last_insert_id()));
-
-    match res {
-        Ok(ins_id) => {
-            let mut ret: Order = o.clone();
-            ret.distance = dist;
-            ret.id = ins_id as i64;
-            ret.received = Some(Local::now().naive_local()); // it is not exactly the same as in DB but good enough for KPIs - client will send it back on PICKUP and COMPLETE
-            return ret;
-        }
-        Err(err) => {
-            println!("{}", err);
-            return Order {
-                ..Default::default()
-            };
-        }
-    }
+    )?;
+    let id: Option<i64> = q.query_first("SELECT LAST_INSERT_ID()")?;
+    let mut ret: Order = *o;
+    ret.distance = dist;
+    ret.id = id.unwrap_or(-1);
+    ret.received = Some(received);
+    ret.est_fare = est_fare;
+    ret.fare = -1;
+    return Ok(ret);
 }
 
 pub fn select_traffik(user_id: i64, c: &mut PooledConn, stand_id: i64) -> StopTraffic {
@@ -554,8 +2022,8 @@ pub fn select_traffik(user_id: i64, c: &mut PooledConn, stand_id: i64) -> StopTr
             to,
             place,
             dist,
-            started,
-            completed,
+            started: Option::<NaiveDateTime>::map(started, from_db),
+            completed: Option::<NaiveDateTime>::map(completed, from_db),
             status: get_route_status(status),
             route_id,
             passengers,
@@ -587,14 +2055,10 @@ pub fn select_traffik(user_id: i64, c: &mut PooledConn, stand_id: i64) -> StopTr
         // the nearest cab should appear first
         routes.sort_by(|a, b| a.eta.cmp(&b.eta));
     }
-    let st = unsafe { STOPS.iter().find(|&x| x.id == stand_id) };
-    let stop: Option<Stop> = match st {
-        Some(s) => Some(s.clone()),
-        None => {
-            println!("Stop ID not found: {}", stop_id);
-            None
-        }
-    };
+    let stop: Option<Stop> = get_stop(stand_id);
+    if stop.is_none() {
+        println!("Stop ID not found: {}", stop_id);
+    }
     // finally find free cabs standing at the stop and waiting for assignments
     let cabs = select_cabs_by_stop(user_id, c, stop_id as i32);
     return StopTraffic { stop, routes, cabs };
@@ -603,11 +2067,11 @@ pub fn select_traffik(user_id: i64, c: &mut PooledConn, stand_id: i64) -> StopTr
 pub fn select_cab_by_route_id(c: &mut PooledConn, id: i64) -> Cab {
     // Cab details
     let res
-        = c.exec_map("SELECT c.id, c.location, c.status, c.seats FROM cab c, route r WHERE r.id=? and c.id = r.cab_id", (id,), 
-        |(id, location, status, seats)| { Cab { id, location, status: get_cab_status(status), seats }});
+        = c.exec_map("SELECT c.id, c.location, c.status, c.seats, c.features FROM cab c, route r WHERE r.id=? and c.id = r.cab_id", (id,), 
+        |(id, location, status, seats, features)| { Cab { id, location, status: get_cab_status(status), seats, features: Features::from_mask(features) }});
     return match res {
-        Ok(row) => row[0],
-        Err(_err) => Cab {
+        Ok(row) if !row.is_empty() => row[0],
+        _ => Cab {
             ..Default::default()
         },
     };
@@ -632,14 +2096,16 @@ pub fn get_route_with_eta(
     };
 }
 
-pub fn select_stats(user_id: i64, c: &mut PooledConn, usr_id: i64) -> Stats {
+pub fn select_stats(user_id: i64, c: &mut PooledConn, q: StatsQuery) -> Stats {
     debug!("select_stats, user_id={}", user_id);
-    if usr_id < 0 {
+    if user_id < 0 {
         // TODO: authorize here too
         return Stats {
             kpis: vec![],
             orders: vec![],
             cabs: vec![],
+            window: None,
+            windowed: vec![],
         };
     }
     let sql = save_status();
@@ -649,10 +2115,21 @@ pub fn select_stats(user_id: i64, c: &mut PooledConn, usr_id: i64) -> Stats {
             warn!("SQL failed to run, err: {}", err);
         }
     }
+    let mut windowed: Vec<Stat> = vec![];
+    if let Some(slots) = q.window.as_deref().and_then(window_slots) {
+        for (key, val) in window_averages(slots) {
+            windowed.push(Stat {
+                name: key.to_string(),
+                int_val: val as i32,
+            });
+        }
+    }
     return Stats {
         kpis: select_stats_kpis(c),
         orders: select_stats_orders(c),
         cabs: select_stats_cabs(c),
+        window: q.window,
+        windowed,
     };
 }
 
@@ -678,16 +2155,279 @@ pub fn select_stats_orders(c: &mut PooledConn) -> Vec<Stat> {
     };
 }
 
+// active cabs by status, also these none is in; deactivated ones apart
 pub fn select_stats_cabs(c: &mut PooledConn) -> Vec<Stat> {
-    let sql = "select status,count(*) from cab group by status".to_string();
-    let res = c.exec_map(sql, (), |(status, int_val)| Stat {
-        name: get_cab_status(status).to_string(),
-        int_val,
+    let sql = "SELECT status, COUNT(*) FROM cab WHERE active=true GROUP BY status".to_string();
+    let counts: HashMap<i32, i32> = match c.query_map(sql, |(status, count)| (status, count)) {
+        Ok(rows) => rows.into_iter().collect(),
+        Err(_) => return Vec::new(),
+    };
+    let mut ret: Vec<Stat> = CAB_STATUSES
+        .iter()
+        .map(|s| Stat {
+            name: s.to_string(),
+            int_val: *counts.get(&(*s as i32)).unwrap_or(&0),
+        })
+        .collect();
+    let inactive: Result<Option<i32>> =
+        c.query_first("SELECT COUNT(*) FROM cab WHERE active=false");
+    if let Ok(Some(count)) = inactive {
+        ret.push(Stat {
+            name: "INACTIVE".to_string(),
+            int_val: count,
+        });
+    }
+    return ret;
+}
+
+pub fn select_quote(user_id: i64, c: &mut PooledConn, q: QuoteRequest) -> Quote {
+    debug!("select_quote, user_id={}", user_id);
+    if q.passengers > select_max_seats(c) {
+        warn!(
+            "Quote refused, user_id={}, no cab can take passengers={}",
+            user_id, q.passengers
+        );
+        // -1 as trip time makes it 400
+        return Quote {
+            from: q.from,
+            to: q.to,
+            passengers: q.passengers,
+            trip_time: -1,
+            eta: -1,
+            pool_eta: -1,
+            pool_trip_time: -1,
+            solo_fare: -1,
+            shared_fare: -1,
+        };
+    }
+    let trip_time = get_dist(q.from, q.to) as i32;
+    let mut quote = Quote {
+        from: q.from,
+        to: q.to,
+        passengers: q.passengers,
+        trip_time,
+        eta: -1,
+        pool_eta: -1,
+        pool_trip_time: -1,
+        solo_fare: estimate_fare(trip_time, false, Utc::now()),
+        shared_fare: estimate_fare(trip_time, true, Utc::now()),
+    };
+    if let Some(cab) = select_free_cabs_near(c, q.from, QUOTE_RADIUS)
+        .iter()
+        .filter(|cab| cab.seats as i32 >= q.passengers && cab.features.covers(&q.requires))
+        .min_by_key(|cab| get_dist(cab.location, q.from))
+    {
+        quote.eta = get_dist(cab.location, q.from) as i32;
+    }
+    if q.shared {
+        let o = Order {
+            from: q.from,
+            to: q.to,
+            wait: q.wait.unwrap_or(i32::MAX),
+            loss: q.loss.unwrap_or(10_000), // percent, as good as no limit
+            distance: trip_time,
+            shared: true,
+            passengers: q.passengers,
+            requires: q.requires,
+            ..Default::default()
+        };
+        if let Some((route, eta)) = find_route_to_join(c, &o) {
+            quote.pool_eta = eta as i32;
+            quote.pool_trip_time = ride_on_route(&route.legs, q.from, q.to);
+        }
+    }
+    return quote;
+}
+
+// seconds of pickup later than max_wait, a scheduled trip is not late before its time
+const DELAY_SQL: &str = "GREATEST(0, TIMESTAMPDIFF(SECOND, GREATEST(received, COALESCE(at_time, received)), started) - max_wait*60)";
+pub const LEADERBOARD_SIZE: i64 = 20;
+
+// distances are kept in minutes of driving
+fn to_km(minutes: i64) -> f64 {
+    return minutes as f64 * CAB_SPEED as f64 / 60.0;
+}
+
+pub fn select_cab_report(user_id: i64, c: &mut PooledConn, id: i64) -> Report {
+    debug!("select_cab_report, user_id={}", user_id);
+    return match select_cab_reports(c, Some(id)).pop() {
+        Some(r) => r,
+        None => Report {
+            id,
+            ..Default::default()
+        },
+    };
+}
+
+// all active cabs, also these which have done nothing - they are the least performing
+pub fn select_leaderboard(user_id: i64, c: &mut PooledConn, q: LeaderboardQuery) -> Vec<Report> {
+    debug!("select_leaderboard, user_id={}", user_id);
+    let mut reports = select_cab_reports(c, None);
+    let by = q.by.unwrap_or("trips".to_string());
+    reports.sort_by(|a, b| {
+        let (x, y) = match by.as_str() {
+            "km" => (a.km, b.km),
+            "idle" => (a.idle_time as f64, b.idle_time as f64),
+            "occupancy" => (a.occupancy, b.occupancy),
+            "delay" => (a.avg_delay as f64, b.avg_delay as f64),
+            _ => (a.trips as f64, b.trips as f64),
+        };
+        return y.total_cmp(&x); // desc
     });
-    return match res {
-        Ok(rows) => rows,
-        Err(_) => Vec::new(),
+    if q.order.as_deref() == Some("asc") {
+        reports.reverse();
+    }
+    reports.truncate(
+        q.limit
+            .unwrap_or(LEADERBOARD_SIZE)
+            .clamp(1, HISTORY_MAX_PAGE_SIZE) as usize,
+    );
+    return reports;
+}
+
+fn select_cab_reports(c: &mut PooledConn, cab_id: Option<i64>) -> Vec<Report> {
+    let mut reports: HashMap<i64, Report> = HashMap::new();
+    let cabs: Vec<i64> = match cab_id {
+        Some(id) => vec![id],
+        None => c
+            .query("SELECT id FROM cab WHERE active=true")
+            .unwrap_or_default(),
+    };
+    for id in cabs {
+        reports.insert(
+            id,
+            Report {
+                id,
+                ..Default::default()
+            },
+        );
+    }
+    // the same cab in every query, each with its own alias
+    let (leg_filter, order_filter, shift_filter) = match cab_id {
+        Some(_) => (" AND r.cab_id=?", " AND o.cab_id=?", " AND s.cab_id=?"),
+        None => ("", "", ""),
+    };
+    let params = match cab_id {
+        Some(id) => Params::Positional(vec![id.into()]),
+        None => Params::Empty,
+    };
+    // cab, distance, occupancy
+    let legs: Result<Vec<(i64, i64, f64)>> = c.exec(
+        "SELECT r.cab_id, SUM(l.distance), AVG(l.passengers) \
+        FROM leg l JOIN route r ON r.id=l.route_id \
+        WHERE l.status=6 AND l.started IS NOT NULL AND l.completed IS NOT NULL"
+            .to_string()
+            + leg_filter
+            + " GROUP BY r.cab_id",
+        params.clone(),
+    ); // 6=COMPLETED
+    match legs {
+        Ok(rows) => {
+            for (id, dist, occupancy) in rows {
+                if let Some(r) = reports.get_mut(&id) {
+                    r.km = to_km(dist);
+                    r.occupancy = occupancy;
+                }
+            }
+        }
+        Err(err) => warn!("Cab reports, legs not read: {}", err),
+    }
+    // idle is the time on shift, breaks excluded, not spent on legs started during the shift;
+    // time off shift does not count, without shifts it is 0
+    let now: Value = Utc::now().naive_utc().into();
+    let mut shift_params: Vec<Value> = vec![now.clone(), now.clone(), now];
+    if let Some(id) = cab_id {
+        shift_params.push(id.into());
+    }
+    let shifts: Result<Vec<(i64, i64, i64)>> = c.exec(
+        "SELECT s.cab_id, \
+            SUM(TIMESTAMPDIFF(SECOND, s.started, COALESCE(s.ended, ?)) \
+                - COALESCE((SELECT SUM(TIMESTAMPDIFF(SECOND, b.started, COALESCE(b.ended, ?))) \
+                    FROM shift_break b WHERE b.shift_id=s.id), 0)), \
+            SUM(COALESCE((SELECT SUM(TIMESTAMPDIFF(SECOND, l.started, l.completed)) \
+                FROM leg l JOIN route r ON r.id=l.route_id \
+                WHERE r.cab_id=s.cab_id AND l.status=6 AND l.completed IS NOT NULL \
+                    AND l.started>=s.started AND l.started<COALESCE(s.ended, ?)), 0)) \
+        FROM shift s WHERE 1=1"
+            .to_string()
+            + shift_filter
+            + " GROUP BY s.cab_id",
+        Params::Positional(shift_params),
+    ); // 6=COMPLETED
+    match shifts {
+        Ok(rows) => {
+            for (id, on_shift, driving) in rows {
+                if let Some(r) = reports.get_mut(&id) {
+                    r.idle_time = cmp::max(0, on_shift - driving);
+                }
+            }
+        }
+        Err(err) => warn!("Cab reports, shifts not read: {}", err),
+    }
+    let orders: Result<Vec<(i64, i64, f64)>> = c.exec(
+        format!(
+            "SELECT o.cab_id, COUNT(*), AVG({}) FROM taxi_order o \
+            WHERE o.status=8 AND o.cab_id IS NOT NULL{} GROUP BY o.cab_id",
+            DELAY_SQL, order_filter
+        ),
+        params,
+    ); // 8=COMPLETED
+    match orders {
+        Ok(rows) => {
+            for (id, trips, delay) in rows {
+                if let Some(r) = reports.get_mut(&id) {
+                    r.trips = trips;
+                    r.avg_delay = delay as i64;
+                }
+            }
+        }
+        Err(err) => warn!("Cab reports, orders not read: {}", err),
+    }
+    return reports.into_values().collect();
+}
+
+pub fn select_customer_report(user_id: i64, c: &mut PooledConn, id: i64) -> Report {
+    debug!("select_customer_report, user_id={}", user_id);
+    let mut report = Report {
+        id,
+        ..Default::default()
     };
+    // trips, distance, wait, delay
+    type OrderTotals = (i64, Option<i64>, Option<i64>, Option<f64>);
+    let orders: Result<Option<OrderTotals>> = c.exec_first(
+        format!(
+            "SELECT COUNT(*), SUM(distance), \
+                SUM(TIMESTAMPDIFF(SECOND, GREATEST(received, COALESCE(at_time, received)), started)), AVG({}) \
+            FROM taxi_order WHERE customer_id=? AND status=8",
+            DELAY_SQL
+        ),
+        (id,),
+    ); // 8=COMPLETED
+    match orders {
+        Ok(Some((trips, dist, wait, delay))) => {
+            report.trips = trips;
+            report.km = to_km(dist.unwrap_or(0));
+            report.idle_time = wait.unwrap_or(0);
+            report.avg_delay = delay.unwrap_or(0.0) as i64;
+        }
+        Ok(None) => {}
+        Err(err) => warn!("Customer report, orders not read: {}", err),
+    }
+    // legs from the pickup leg to the one that ends at the destination
+    let occupancy: Result<Option<Option<f64>>> = c.exec_first(
+        "SELECT AVG(l.passengers) FROM taxi_order o \
+            JOIN leg f ON f.id=o.leg_id \
+            JOIN leg l ON l.route_id=o.route_id AND l.place>=f.place AND l.place<=( \
+                SELECT MIN(t.place) FROM leg t WHERE t.route_id=o.route_id AND t.place>=f.place AND t.to_stand=o.to_stand) \
+        WHERE o.customer_id=? AND o.status=8",
+        (id,),
+    );
+    match occupancy {
+        Ok(Some(Some(val))) => report.occupancy = val,
+        Ok(_) => {}
+        Err(err) => warn!("Customer report, legs not read: {}", err),
+    }
+    return report;
 }
 
 pub fn calculate_eta(stand_id: i32, route: &Route) -> i16 {
@@ -701,42 +2441,47 @@ pub fn calculate_eta(stand_id: i32, route: &Route) -> i16 {
             break; // if standId happens to be toStand in the last leg and
                    // this break never occurs - that is just OK
         }
-        // there are two situations - active (currently executed) leg and legs waiting for pick-up
-        //let distance = unsafe { DIST[leg.From][leg.To] };
-        if leg.status == RouteStatus::STARTED {
-            if leg.started == None {
-                // some error
-                eta += leg.dist + STOP_WAIT;
-            } else {
-                let minutes: i32 = (get_elapsed(leg.started) / 60) as i32;
-                if minutes != -1 {
-                    eta += cmp::max(leg.dist - minutes, 0);
-                }
-                // it has taken longer than planned
-                // TASK: assumption 1km = 1min, see also CabRunnable: waitMins(getDistance
-            }
-        } else if leg.status == RouteStatus::ASSIGNED {
-            eta += leg.dist + STOP_WAIT;
-        }
+        eta += minutes_of_leg(&leg);
     }
     return eta as i16 - STOP_WAIT as i16; // minus wait time at the stand_id
 }
 
-pub fn get_elapsed(val: Option<NaiveDateTime>) -> i64 {
-    match val {
-        Some(x) => {
-            let now = Local::now().naive_local();
-            return (now - x).num_seconds();
+// as 'calculate_eta', but to the start of the leg 'leg_id', earlier legs from the same stop do not stop it
+fn eta_to_leg(legs: &[Leg], leg_id: i64) -> i16 {
+    let eta: i32 = legs
+        .iter()
+        .take_while(|l| l.id != leg_id)
+        .map(minutes_of_leg)
+        .sum();
+    return eta as i16 - STOP_WAIT as i16;
+}
+
+// what is left of a leg, the stop at its end included
+fn minutes_of_leg(leg: &Leg) -> i32 {
+    // there are two situations - active (currently executed) leg and legs waiting for pick-up
+    //let distance = unsafe { DIST[leg.From][leg.To] };
+    if leg.status == RouteStatus::STARTED {
+        if leg.started.is_none() {
+            // some error
+            return leg.dist + STOP_WAIT;
         }
-        None => -1,
+        let minutes: i32 = (get_elapsed(leg.started) / 60) as i32;
+        if minutes != -1 {
+            return cmp::max(leg.dist - minutes, 0);
+        }
+        // it has taken longer than planned
+        // TASK: assumption 1km = 1min, see also CabRunnable: waitMins(getDistance
+    } else if leg.status == RouteStatus::ASSIGNED {
+        return leg.dist + STOP_WAIT;
     }
+    return 0;
 }
 
-pub fn get_elapsed_dt(val: Option<NaiveDateTime>) -> i64 {
+// seconds since 'val', -1 if it has not happened
+pub fn get_elapsed(val: Option<DateTime<Utc>>) -> i64 {
     match val {
         Some(x) => {
-            let t = Local::now().time() - x.time();
-            t.num_seconds()
+            return (Utc::now() - x).num_seconds();
         }
         None => -1,
     }
@@ -750,27 +2495,352 @@ fn check_result(res: Result<QueryResult<'_, '_, '_, Binary>>) -> u64 {
         }
         Err(err) => {
             println!("{}", err);
+            query_failed();
             0
         }
     };
 }
 
-pub async fn init_read_stops(mut client: PooledConn) {
-    let res = client.exec_map(
-        "SELECT id, latitude, longitude, bearing, name FROM stop",
+// kapir is useless without stops, the caller should not start serving if this fails
+pub async fn init_read_stops(mut client: PooledConn) -> std::result::Result<usize, String> {
+    return match read_stops(&mut client) {
+        Ok((stops, retired)) => {
+            let count = stops.len();
+            if count == 0 {
+                return Err("no active stops found in the DB".to_string());
+            }
+            reload_distance(stops, &retired);
+            Ok(count)
+        }
+        Err(err) => Err(format!("stops could not be read: {}", err)),
+    };
+}
+
+// is the DB reachable
+pub fn ping_db(c: &mut PooledConn) -> bool {
+    let res: Result<Option<i32>> = c.query_first("SELECT 1");
+    return res.is_ok();
+}
+
+// active and retired stops
+fn read_stops(c: &mut PooledConn) -> Result<(Vec<Stop>, Vec<Stop>)> {
+    let rows: Vec<(Stop, bool)> = c.exec_map(
+        "SELECT id, latitude, longitude, bearing, name, active FROM stop",
         (),
-        |(id, latitude, longitude, bearing, name)| Stop {
-            id,
-            latitude,
-            longitude,
-            bearing,
-            name: Some(name),
+        |(id, latitude, longitude, bearing, name, active)| {
+            (
+                Stop {
+                    id,
+                    latitude,
+                    longitude,
+                    bearing,
+                    name: Some(name),
+                },
+                active,
+            )
         },
-    );
-    match res {
-        Ok(rows) => unsafe {
-            STOPS = rows;
+    )?;
+    let (active, retired): (Vec<(Stop, bool)>, Vec<(Stop, bool)>) =
+        rows.into_iter().partition(|(_, active)| *active);
+    return Ok((
+        active.into_iter().map(|(s, _)| s).collect(),
+        retired.into_iter().map(|(s, _)| s).collect(),
+    ));
+}
+
+// ADMIN, the network can be changed during the day
+pub fn reload_stops(user_id: i64, c: &mut PooledConn, _id: i64) -> usize {
+    return match read_stops(c) {
+        Ok((stops, _)) if stops.is_empty() => {
+            warn!("No active stops found, the old ones stay");
+            0
+        }
+        Ok((stops, retired)) => {
+            let count = stops.len();
+            reload_distance(stops, &retired);
+            info!("Stops reloaded, user_id={}, count={}", user_id, count);
+            count
+        }
+        Err(err) => {
+            warn!(
+                "Stops could not be reloaded, the old ones stay, err: {}",
+                err
+            );
+            0
+        }
+    };
+}
+
+fn is_stop_valid(stop: &Stop) -> bool {
+    return stop.id >= 0
+        && (stop.id as usize) < MAXSTOPSNUMB
+        && stop.latitude >= -90.0
+        && stop.latitude <= 90.0
+        && stop.longitude >= -180.0
+        && stop.longitude <= 180.0
+        && stop.bearing >= -180
+        && stop.bearing <= 360;
+}
+
+pub fn insert_stop(user_id: i64, c: &mut PooledConn, stop: Stop) -> Stop {
+    if !is_stop_valid(&stop) {
+        warn!(
+            "insert_stop refused, user_id={}, stop_id={}",
+            user_id, stop.id
+        );
+        return Stop {
+            ..Default::default()
+        };
+    }
+    let res = c.exec_drop(
+        "INSERT INTO stop (id, no, name, latitude, longitude, bearing, active) VALUES ( \
+                :id, :no, :name, :latitude, :longitude, :bearing, true)",
+        params! {
+            "id" => stop.id,
+            "no" => stop.id.to_string(),
+            "name" => &stop.name,
+            "latitude" => stop.latitude,
+            "longitude" => stop.longitude,
+            "bearing" => stop.bearing
         },
-        Err(_) => {}
+    );
+    return match res {
+        Ok(_) => {
+            reload_stops(user_id, c, stop.id);
+            stop
+        }
+        Err(err) => {
+            warn!("insert_stop failed, stop_id={}, err: {}", stop.id, err);
+            Stop {
+                ..Default::default()
+            }
+        }
+    };
+}
+
+pub fn update_stop(user_id: i64, c: &mut PooledConn, stop: Stop) -> Stop {
+    if !is_stop_valid(&stop) {
+        warn!(
+            "update_stop refused, user_id={}, stop_id={}",
+            user_id, stop.id
+        );
+        return Stop {
+            ..Default::default()
+        };
+    }
+    let count = check_result(c.exec_iter(
+        "UPDATE stop SET name=?, latitude=?, longitude=?, bearing=? WHERE id=? AND active=true",
+        (
+            &stop.name,
+            stop.latitude,
+            stop.longitude,
+            stop.bearing,
+            stop.id,
+        ),
+    ));
+    if count == 0 {
+        return Stop {
+            ..Default::default()
+        };
+    }
+    reload_stops(user_id, c, stop.id);
+    return stop;
+}
+
+// a retired stop stays in the DB for the history of orders, but new orders cannot use it
+pub fn retire_stop(user_id: i64, c: &mut PooledConn, id: i64) -> Stop {
+    let stop = match get_stop(id) {
+        Some(s) => s,
+        None => {
+            return Stop {
+                ..Default::default()
+            }
+        }
+    };
+    let count = check_result(c.exec_iter("UPDATE stop SET active=false WHERE id=?", (id,)));
+    if count == 0 {
+        return Stop {
+            ..Default::default()
+        };
+    }
+    reload_stops(user_id, c, id);
+    return stop;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_stops, detours_acceptable, drop_stops, eta_to_leg, find_leg_to_join, STOP_WAIT,
     };
+    use crate::distance::{get_dist, get_stop, reload_distance};
+    use crate::model::{Leg, Order, OrderStatus, Route, RouteStatus, Stop};
+    use std::sync::Once;
+
+    static NETWORK: Once = Once::new();
+
+    // stops 1..5 in a line about two minutes apart, 6 a bit off the line between 1 and 5,
+    // 7 retired after 5
+    fn network() {
+        NETWORK.call_once(|| {
+            let mut stops: Vec<Stop> = (1..=5)
+                .map(|id| Stop {
+                    id,
+                    latitude: (id - 1) as f64 * 0.01,
+                    ..Default::default()
+                })
+                .collect();
+            stops.push(Stop {
+                id: 6,
+                latitude: 0.02,
+                longitude: 0.01,
+                ..Default::default()
+            });
+            let retired = Stop {
+                id: 7,
+                latitude: 0.05,
+                ..Default::default()
+            };
+            reload_distance(stops, &[retired]);
+        });
+    }
+
+    fn legs(stops: &[i32]) -> Vec<Leg> {
+        return stops
+            .windows(2)
+            .enumerate()
+            .map(|(place, w)| Leg {
+                id: 10 + place as i64,
+                route_id: 1,
+                from: w[0],
+                to: w[1],
+                place: place as i32,
+                dist: get_dist(w[0], w[1]) as i32,
+                started: None,
+                completed: None,
+                status: RouteStatus::ASSIGNED,
+                passengers: 1,
+            })
+            .collect();
+    }
+
+    fn stops_of(legs: &[Leg]) -> Vec<(i32, i32, i32)> {
+        return legs.iter().map(|l| (l.from, l.to, l.place)).collect();
+    }
+
+    #[test]
+    fn stops_already_on_route() {
+        network();
+        let mut route = legs(&[1, 2, 3]);
+        assert!(!add_stops(&mut route, 1, 3));
+        assert_eq!(stops_of(&route), vec![(1, 2, 0), (2, 3, 1)]);
+        assert!(route.iter().all(|l| l.id != 0));
+    }
+
+    #[test]
+    fn stop_splits_leg_in_the_middle() {
+        network();
+        let mut route = legs(&[1, 3]);
+        assert!(add_stops(&mut route, 1, 2));
+        assert_eq!(stops_of(&route), vec![(1, 2, 0), (2, 3, 1)]);
+        assert_eq!(route[0].id, 10);
+        assert_eq!(route[1].id, 0);
+        assert_eq!(route[0].dist, get_dist(1, 2) as i32);
+        assert_eq!(route[1].dist, get_dist(2, 3) as i32);
+    }
+
+    #[test]
+    fn stop_appended_at_the_end() {
+        network();
+        let mut route = legs(&[1, 2]);
+        assert!(add_stops(&mut route, 2, 4));
+        assert_eq!(stops_of(&route), vec![(1, 2, 0), (2, 4, 1)]);
+        assert_eq!(route[1].id, 0);
+        assert_eq!(route[1].dist, get_dist(2, 4) as i32);
+    }
+
+    #[test]
+    fn detour_beyond_max_loss_rejected() {
+        network();
+        let route = Route {
+            id: 1,
+            status: RouteStatus::ASSIGNED,
+            legs: legs(&[1, 5]),
+            ..Default::default()
+        };
+        let mut extended = route.legs.clone();
+        assert!(add_stops(&mut extended, 1, 6));
+        assert_eq!(stops_of(&extended), vec![(1, 6, 0), (6, 5, 1)]);
+
+        let mut order = Order {
+            id: 100,
+            from: 1,
+            to: 5,
+            distance: get_dist(1, 5) as i32,
+            status: OrderStatus::PICKEDUP,
+            route_id: 1,
+            leg_id: 10,
+            loss: 0,
+            ..Default::default()
+        };
+        assert!(!detours_acceptable(&route, &extended, &[order]));
+        order.loss = 100;
+        assert!(detours_acceptable(&route, &extended, &[order]));
+    }
+
+    fn with_passengers(mut legs: Vec<Leg>, passengers: &[i32]) -> Vec<Leg> {
+        for (l, p) in legs.iter_mut().zip(passengers) {
+            l.passengers = *p;
+        }
+        return legs;
+    }
+
+    #[test]
+    fn empty_pickup_leg_in_the_middle_merged() {
+        network();
+        // the cancelled customer rode 2->3, another one rides 3->4
+        let mut route = with_passengers(legs(&[1, 2, 3, 4]), &[0, 0, 1]);
+        assert_eq!(drop_stops(&mut route, &[3, 4]), vec![11]);
+        assert_eq!(stops_of(&route), vec![(1, 3, 0), (3, 4, 1)]);
+        assert_eq!(route[0].dist, get_dist(1, 3) as i32);
+    }
+
+    #[test]
+    fn empty_legs_at_the_end_dropped() {
+        network();
+        let mut route = with_passengers(legs(&[1, 2, 3, 4]), &[1, 0, 0]);
+        assert_eq!(drop_stops(&mut route, &[1, 2]), vec![12, 11]);
+        assert_eq!(stops_of(&route), vec![(1, 2, 0)]);
+        assert!(drop_stops(&mut route, &[]).is_empty());
+    }
+
+    #[test]
+    fn started_leg_kept() {
+        network();
+        let mut route = with_passengers(legs(&[1, 2, 3]), &[0, 0]);
+        route[0].status = RouteStatus::STARTED;
+        assert_eq!(drop_stops(&mut route, &[]), vec![11]);
+        assert_eq!(stops_of(&route), vec![(1, 2, 0)]);
+    }
+
+    #[test]
+    fn retired_stop_keeps_distance() {
+        network();
+        assert!(get_stop(7).is_none());
+        assert!(get_stop(5).is_some());
+        assert!(get_dist(5, 7) > 0);
+        assert_eq!(get_dist(5, 7), get_dist(7, 5));
+    }
+
+    #[test]
+    fn eta_to_the_visit_ahead() {
+        network();
+        // the route has passed 2 already and comes back to it
+        let mut route = legs(&[1, 2, 3, 2, 4]);
+        route[0].status = RouteStatus::COMPLETED;
+        route[1].status = RouteStatus::COMPLETED;
+        let leg_id = find_leg_to_join(&route, 2);
+        assert_eq!(leg_id, 13);
+        let eta = route[2].dist + STOP_WAIT;
+        assert_eq!(eta_to_leg(&route, leg_id) as i32, eta - STOP_WAIT);
+    }
 }
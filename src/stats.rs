@@ -2,22 +2,208 @@ use self::Stat::*;
 use log::info;
 use std::fmt;
 use std::slice::Iter;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
 
-pub static mut STATS: [i64; AvgOrderCompleteTime as usize + 1] =
-    [0; AvgOrderCompleteTime as usize + 1];
-const INT: Vec<i64> = vec![];
-pub static mut AVG_ELEMENTS: [Vec<i64>; AvgOrderCompleteTime as usize + 1] =
-    [INT; AvgOrderCompleteTime as usize + 1];
+const STAT_COUNT: usize = OrderPooledShare as usize + 1;
+pub static mut STATS: [i64; STAT_COUNT] = [0; STAT_COUNT];
+
+// relative error of a percentile is below (GAMMA-1)/2, i.e. 1%
+const GAMMA: f64 = 1.02;
+// log(7 days in seconds)/log(GAMMA) is ~680, longer times land in the last bucket
+const BUCKETS: usize = 800;
+
+// Count, sum and a log-bucketed histogram of times (in seconds) - memory does not grow
+// with the number of orders, as it did when all elements were kept for averages
+struct Sketch {
+    count: u64,
+    sum: i64,
+    zeros: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl Sketch {
+    const fn new() -> Self {
+        Sketch {
+            count: 0,
+            sum: 0,
+            zeros: 0,
+            buckets: [0; BUCKETS],
+        }
+    }
+
+    fn add(&mut self, value: i64) {
+        self.count += 1;
+        self.sum += value;
+        if value <= 0 {
+            self.zeros += 1;
+            return;
+        }
+        let idx = ((value as f64).ln() / GAMMA.ln()).ceil() as usize;
+        self.buckets[idx.min(BUCKETS - 1)] += 1;
+    }
+
+    fn average(&self) -> i64 {
+        if self.count == 0 {
+            return 0;
+        }
+        return self.sum / self.count as i64;
+    }
+
+    // 'q' in percent, e.g. 90
+    fn percentile(&self, q: u64) -> i64 {
+        if self.count == 0 {
+            return 0;
+        }
+        // rank of the element, counted from 1
+        let rank = (self.count * q).div_ceil(100).max(1);
+        let mut seen = self.zeros;
+        if seen >= rank {
+            return 0;
+        }
+        for (idx, cnt) in self.buckets.iter().enumerate() {
+            seen += cnt;
+            if seen >= rank {
+                // middle of the bucket (GAMMA^(idx-1), GAMMA^idx]
+                return (2.0 * GAMMA.powi(idx as i32) / (GAMMA + 1.0)).round() as i64;
+            }
+        }
+        return 0;
+    }
+}
+
+static PICKUP_TIMES: Mutex<Sketch> = Mutex::new(Sketch::new());
+static COMPLETE_TIMES: Mutex<Sketch> = Mutex::new(Sketch::new());
+static WAIT_TIMES: Mutex<Sketch> = Mutex::new(Sketch::new());
+static DETOURS: Mutex<Sketch> = Mutex::new(Sketch::new());
+static WAITS_OF_MAX: Mutex<Sketch> = Mutex::new(Sketch::new());
+static DETOURS_OF_LOSS: Mutex<Sketch> = Mutex::new(Sketch::new());
+static POOLED: Mutex<Sketch> = Mutex::new(Sketch::new());
+
+// Rolling windows: the last hour is split into slots of 10 seconds, each slot keeps
+// count and sum of every windowed KPI; a slot is reused when the hour comes round
+const SLOT_SECS: u64 = 10;
+const SLOTS: usize = 360;
+const WINDOWED: [Stat; 7] = [
+    AvgOrderPickupTime,
+    AvgOrderCompleteTime,
+    AvgOrderWaitTime,
+    AvgOrderDetour,
+    AvgOrderWaitOfMax,
+    AvgOrderDetourOfLoss,
+    OrderPooledShare,
+];
+
+#[derive(Copy, Clone)]
+struct Slot {
+    number: u64, // which 10 seconds since start
+    count: [u64; STAT_COUNT],
+    sum: [i64; STAT_COUNT],
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    number: u64::MAX,
+    count: [0; STAT_COUNT],
+    sum: [0; STAT_COUNT],
+};
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+static WINDOW: Mutex<[Slot; SLOTS]> = Mutex::new([EMPTY_SLOT; SLOTS]);
+
+fn current_slot() -> u64 {
+    return START.elapsed().as_secs() / SLOT_SECS;
+}
+
+fn add_to_window(key: Stat, value: i64) {
+    add_to_slot(&mut WINDOW.lock().unwrap(), current_slot(), key, value);
+}
+
+fn add_to_slot(window: &mut [Slot; SLOTS], number: u64, key: Stat, value: i64) {
+    let slot = &mut window[number as usize % SLOTS];
+    if slot.number != number {
+        *slot = EMPTY_SLOT;
+        slot.number = number;
+    }
+    slot.count[key as usize] += 1;
+    slot.sum[key as usize] += value;
+}
+
+// "1m", "15m" or "1h" -> number of slots
+pub fn window_slots(window: &str) -> Option<u64> {
+    let secs = match window {
+        "1m" => 60,
+        "15m" => 15 * 60,
+        "1h" => 60 * 60,
+        _ => return None,
+    };
+    return Some(secs / SLOT_SECS);
+}
+
+// averages of the windowed KPIs in the last 'slots', the current slot included
+pub fn window_averages(slots: u64) -> Vec<(Stat, i64)> {
+    return averages_of_slots(&WINDOW.lock().unwrap(), current_slot(), slots);
+}
+
+// 'number' is the current slot
+fn averages_of_slots(window: &[Slot; SLOTS], number: u64, slots: u64) -> Vec<(Stat, i64)> {
+    let oldest = (number + 1).saturating_sub(slots);
+    let mut count = [0u64; STAT_COUNT];
+    let mut sum = [0i64; STAT_COUNT];
+    for slot in window.iter() {
+        if slot.number == u64::MAX || slot.number < oldest || slot.number > number {
+            continue;
+        }
+        for k in WINDOWED {
+            count[k as usize] += slot.count[k as usize];
+            sum[k as usize] += slot.sum[k as usize];
+        }
+    }
+    let mut ret: Vec<(Stat, i64)> = vec![];
+    for k in WINDOWED {
+        let avg = if count[k as usize] == 0 {
+            0
+        } else {
+            sum[k as usize] / count[k as usize] as i64
+        };
+        ret.push((k, avg));
+    }
+    return ret;
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum Stat {
     AvgOrderPickupTime,
     AvgOrderCompleteTime,
+    OrderPickupTimeP50,
+    OrderPickupTimeP90,
+    OrderPickupTimeP99,
+    OrderCompleteTimeP50,
+    OrderCompleteTimeP90,
+    OrderCompleteTimeP99,
+    AvgOrderWaitTime,
+    AvgOrderDetour,       // percent of ride time above the direct distance
+    AvgOrderWaitOfMax,    // wait in percent of max_wait the customer accepted
+    AvgOrderDetourOfLoss, // detour in percent of max_loss
+    OrderPooledShare,     // percent of completed trips that were shared
 }
 
 impl Stat {
     pub fn iterator() -> Iter<'static, Stat> {
-        static RET: [Stat; 2] = [AvgOrderPickupTime, AvgOrderCompleteTime];
+        static RET: [Stat; STAT_COUNT] = [
+            AvgOrderPickupTime,
+            AvgOrderCompleteTime,
+            OrderPickupTimeP50,
+            OrderPickupTimeP90,
+            OrderPickupTimeP99,
+            OrderCompleteTimeP50,
+            OrderCompleteTimeP90,
+            OrderCompleteTimeP99,
+            AvgOrderWaitTime,
+            AvgOrderDetour,
+            AvgOrderWaitOfMax,
+            AvgOrderDetourOfLoss,
+            OrderPooledShare,
+        ];
         RET.iter()
     }
 }
@@ -36,38 +222,38 @@ pub fn update_val(key: Stat, value: i64) {
     }
 }
 
-pub fn add_avg_element(key: Stat, time: i64) {
-    unsafe {
-        AVG_ELEMENTS[key as usize].push(time);
-    }
-}
-
-pub fn count_average(key: Stat) -> i64 {
-    unsafe {
-        let list: Vec<i64> = AVG_ELEMENTS[key as usize].to_vec();
-        let length = list.len();
-        if length == 0 {
-            return 0;
-        }
-        let mut suma: i64 = 0;
-        for i in list {
-            suma += i;
-        }
-
-        return (suma / length as i64) as i64;
-    }
+fn update_from_sketch(sketch: &Mutex<Sketch>, avg: Stat, p50: Stat, p90: Stat, p99: Stat) {
+    let sketch = sketch.lock().unwrap();
+    update_val(avg, sketch.average());
+    update_val(p50, sketch.percentile(50));
+    update_val(p90, sketch.percentile(90));
+    update_val(p99, sketch.percentile(99));
 }
 
 pub fn save_status() -> String {
     let mut sql: String = String::from("");
-    update_val(
-        Stat::AvgOrderPickupTime,
-        count_average(Stat::AvgOrderPickupTime),
+    update_from_sketch(
+        &PICKUP_TIMES,
+        AvgOrderPickupTime,
+        OrderPickupTimeP50,
+        OrderPickupTimeP90,
+        OrderPickupTimeP99,
     );
+    update_from_sketch(
+        &COMPLETE_TIMES,
+        AvgOrderCompleteTime,
+        OrderCompleteTimeP50,
+        OrderCompleteTimeP90,
+        OrderCompleteTimeP99,
+    );
+    update_val(AvgOrderWaitTime, WAIT_TIMES.lock().unwrap().average());
+    update_val(AvgOrderDetour, DETOURS.lock().unwrap().average());
+    update_val(AvgOrderWaitOfMax, WAITS_OF_MAX.lock().unwrap().average());
     update_val(
-        Stat::AvgOrderCompleteTime,
-        count_average(Stat::AvgOrderCompleteTime),
+        AvgOrderDetourOfLoss,
+        DETOURS_OF_LOSS.lock().unwrap().average(),
     );
+    update_val(OrderPooledShare, POOLED.lock().unwrap().average());
     unsafe {
         for s in Stat::iterator() {
             sql += &format!(
@@ -84,7 +270,8 @@ pub fn add_avg_pickup(value: i64) {
     if value == -1 {
         info!("Warn: add_avg_pickup called with -1");
     } else {
-        add_avg_element(Stat::AvgOrderPickupTime, value);
+        PICKUP_TIMES.lock().unwrap().add(value);
+        add_to_window(AvgOrderPickupTime, value);
     }
 }
 
@@ -92,6 +279,121 @@ pub fn add_avg_complete(value: i64) {
     if value == -1 {
         info!("Warn: add_avg_complete called with -1");
     } else {
-        add_avg_element(Stat::AvgOrderCompleteTime, value);
+        COMPLETE_TIMES.lock().unwrap().add(value);
+        add_to_window(AvgOrderCompleteTime, value);
+    }
+}
+
+// from the requested time (received or AtTime for scheduled trips) to pickup
+pub fn add_avg_wait(value: i64) {
+    if value == -1 {
+        info!("Warn: add_avg_wait called with -1");
+    } else {
+        WAIT_TIMES.lock().unwrap().add(value);
+        add_to_window(AvgOrderWaitTime, value);
+    }
+}
+
+pub fn add_avg_detour(value: i64) {
+    DETOURS.lock().unwrap().add(value);
+    add_to_window(AvgOrderDetour, value);
+}
+
+pub fn add_avg_wait_of_max(value: i64) {
+    WAITS_OF_MAX.lock().unwrap().add(value);
+    add_to_window(AvgOrderWaitOfMax, value);
+}
+
+pub fn add_avg_detour_of_loss(value: i64) {
+    DETOURS_OF_LOSS.lock().unwrap().add(value);
+    add_to_window(AvgOrderDetourOfLoss, value);
+}
+
+// the average of 100s and 0s is the share in percent
+pub fn add_completed_trip(in_pool: bool) {
+    let value = if in_pool { 100 } else { 0 };
+    POOLED.lock().unwrap().add(value);
+    add_to_window(OrderPooledShare, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn average_of(averages: &[(Stat, i64)], key: Stat) -> i64 {
+        return averages
+            .iter()
+            .find(|(k, _)| *k as usize == key as usize)
+            .map(|(_, v)| *v)
+            .unwrap();
+    }
+
+    #[test]
+    fn percentile_within_error_bound() {
+        let mut sketch = Sketch::new();
+        for v in 1..=10000 {
+            sketch.add(v);
+        }
+        assert_eq!(sketch.average(), 5000);
+        for (q, exact) in [(50, 5000), (90, 9000), (99, 9900)] {
+            let p = sketch.percentile(q);
+            assert!(
+                (p - exact).abs() as f64 <= exact as f64 * (GAMMA - 1.0) / 2.0 + 1.0,
+                "p{}={} exact={}",
+                q,
+                p,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn zero_and_negative_values() {
+        let mut sketch = Sketch::new();
+        assert_eq!(sketch.percentile(50), 0);
+        assert_eq!(sketch.average(), 0);
+        sketch.add(0);
+        sketch.add(-5);
+        sketch.add(100);
+        assert_eq!(sketch.average(), 31);
+        assert_eq!(sketch.percentile(50), 0);
+        assert_eq!(sketch.percentile(99), 100);
+    }
+
+    #[test]
+    fn window_drops_old_slots() {
+        let mut window = [EMPTY_SLOT; SLOTS];
+        add_to_slot(&mut window, 0, AvgOrderWaitTime, 10);
+        add_to_slot(&mut window, 6, AvgOrderWaitTime, 20);
+        add_to_slot(&mut window, 6, AvgOrderWaitTime, 40);
+        // slots 1..=6
+        assert_eq!(
+            average_of(&averages_of_slots(&window, 6, 6), AvgOrderWaitTime),
+            30
+        );
+        // slots 0..=6
+        assert_eq!(
+            average_of(&averages_of_slots(&window, 6, 7), AvgOrderWaitTime),
+            23
+        );
+        assert_eq!(
+            average_of(&averages_of_slots(&window, 6, 7), AvgOrderDetour),
+            0
+        );
+    }
+
+    #[test]
+    fn window_reuses_slot_after_an_hour() {
+        let mut window = [EMPTY_SLOT; SLOTS];
+        add_to_slot(&mut window, 5, AvgOrderDetour, 50);
+        add_to_slot(&mut window, 5 + SLOTS as u64, AvgOrderDetour, 10);
+        let hour = window_slots("1h").unwrap();
+        assert_eq!(
+            average_of(
+                &averages_of_slots(&window, 5 + SLOTS as u64, hour),
+                AvgOrderDetour
+            ),
+            10
+        );
     }
 }
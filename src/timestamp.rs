@@ -0,0 +1,105 @@
+use crate::version::is_v2;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Timestamps are kept in UTC, also in the DB. Responses show them in this zone, in seconds
+// east of UTC - 'display_zone' in kapir.toml, e.g. "+02:00"
+pub static mut DISPLAY_ZONE: i32 = 0;
+
+pub fn display_zone() -> FixedOffset {
+    return FixedOffset::east_opt(unsafe { DISPLAY_ZONE }).unwrap();
+}
+
+pub fn set_display_zone(zone: &str) -> Result<(), String> {
+    match zone.parse::<FixedOffset>() {
+        Ok(offset) => {
+            unsafe {
+                DISPLAY_ZONE = offset.local_minus_utc();
+            }
+            return Ok(());
+        }
+        Err(err) => return Err(format!("Wrong display_zone {}: {}", zone, err)),
+    }
+}
+
+// DATETIME columns carry no zone, they are UTC
+pub fn from_db(val: NaiveDateTime) -> DateTime<Utc> {
+    return val.and_utc();
+}
+
+// RFC 3339 with any offset; a time without offset, as older clients send it, is in the display zone,
+// with 'T' or a space between the date and the time ("2025-05-02 11:52:04", see tests/java)
+pub fn parse(val: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(val) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let naive = val
+        .parse::<NaiveDateTime>()
+        .or_else(|_| NaiveDateTime::parse_from_str(val, "%Y-%m-%d %H:%M:%S%.f"));
+    return match naive {
+        Ok(t) => match display_zone().from_local_datetime(&t).single() {
+            Some(t) => Ok(t.with_timezone(&Utc)),
+            None => Err(format!("Ambiguous time {}", val)),
+        },
+        Err(err) => Err(format!("Wrong time {}: {}", val, err)),
+    };
+}
+
+// v1 keeps the format it always had, "2025-05-02T11:52:04" as chrono writes a NaiveDateTime;
+// only v2 adds the offset
+pub fn serialize<S: Serializer>(val: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    let local = val.with_timezone(&display_zone());
+    if is_v2() {
+        return s.serialize_str(&local.to_rfc3339());
+    }
+    return local.naive_local().serialize(s);
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+    let val = String::deserialize(d)?;
+    return parse(&val).map_err(serde::de::Error::custom);
+}
+
+// the same for optional fields, use with #[serde(default)]
+pub mod opt {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(val: &Option<DateTime<Utc>>, s: S) -> Result<S::Ok, S::Error> {
+        return match val {
+            Some(t) => super::serialize(t, s),
+            None => s.serialize_none(),
+        };
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        return match Option::<String>::deserialize(d)? {
+            Some(val) => super::parse(&val)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+            None => Ok(None),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, serialize};
+
+    #[test]
+    fn parse_with_and_without_offset() {
+        let utc = parse("2025-05-02T11:52:04Z").unwrap();
+        assert_eq!(parse("2025-05-02T13:52:04+02:00").unwrap(), utc);
+        // no offset, the display zone is UTC by default
+        assert_eq!(parse("2025-05-02T11:52:04").unwrap(), utc);
+        assert_eq!(parse("2025-05-02 11:52:04").unwrap(), utc);
+        assert!(parse("2025-05-02").is_err());
+    }
+
+    #[test]
+    fn v1_without_offset() {
+        let t = parse("2025-05-02T11:52:04Z").unwrap();
+        let json = serialize(&t, serde_json::value::Serializer).unwrap();
+        assert_eq!(json, "2025-05-02T11:52:04");
+    }
+}
@@ -0,0 +1,180 @@
+use crate::model::{
+    Cab, CabInfo, CabStatus, Leg, Order, OrderHistory, Quote, Report, Route, RouteWithOrders,
+    Shift, ShiftReport, Stats, Stop, StopTraffic,
+};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse};
+use serde::Serialize;
+
+// v1 encodes what is missing as -1 (ids, location, eta, fares) and answers a failed request
+// with a default object. Under /v2 the same models leave such fields out and a failure is an error.
+// The switch is per request, set by this middleware for the /v2 scope and read while serializing;
+// it keeps the method, a GET of what does not exist is 404 rather than 422.
+tokio::task_local! {
+    static V2: Method;
+}
+
+pub async fn api_v2(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let method = req.method().clone();
+    return V2.scope(method, next.call(req)).await;
+}
+
+pub fn is_v2() -> bool {
+    return V2.try_with(|_| ()).is_ok();
+}
+
+pub trait Absent {
+    fn absent(&self) -> bool;
+}
+
+impl Absent for i64 {
+    fn absent(&self) -> bool {
+        return *self == -1;
+    }
+}
+
+impl Absent for i32 {
+    fn absent(&self) -> bool {
+        return *self == -1;
+    }
+}
+
+impl Absent for i8 {
+    fn absent(&self) -> bool {
+        return *self == -1;
+    }
+}
+
+impl Absent for CabStatus {
+    fn absent(&self) -> bool {
+        return *self == CabStatus::UNKNOWN;
+    }
+}
+
+impl Absent for Cab {
+    fn absent(&self) -> bool {
+        return self.id == -1;
+    }
+}
+
+// #[serde(skip_serializing_if = "crate::version::absent")], the field is left out in v2 only
+pub fn absent<T: Absent>(val: &T) -> bool {
+    return is_v2() && val.absent();
+}
+
+// what a handler sends back; in v2 a refused or missing object is an error
+pub trait Outcome {
+    fn error(&self) -> Option<&'static str> {
+        return None;
+    }
+
+    // a request that cannot be served in any version, 400 in v1 too
+    fn invalid(&self) -> Option<&'static str> {
+        return None;
+    }
+}
+
+impl Outcome for bool {
+    fn error(&self) -> Option<&'static str> {
+        return if *self { None } else { Some("Refused") };
+    }
+}
+
+impl Outcome for Order {
+    fn error(&self) -> Option<&'static str> {
+        return if self.id == -1 {
+            Some("Order refused or not found")
+        } else {
+            None
+        };
+    }
+}
+
+impl Outcome for Cab {
+    fn error(&self) -> Option<&'static str> {
+        return if self.id == -1 {
+            Some("Cab not found")
+        } else {
+            None
+        };
+    }
+}
+
+impl Outcome for CabInfo {
+    fn error(&self) -> Option<&'static str> {
+        return self.cab.error();
+    }
+}
+
+impl Outcome for Shift {
+    fn error(&self) -> Option<&'static str> {
+        return if self.id == -1 {
+            Some("No shift or the cab is assigned")
+        } else {
+            None
+        };
+    }
+}
+
+impl Outcome for Route {
+    fn error(&self) -> Option<&'static str> {
+        return if self.id == -1 {
+            Some("No route")
+        } else {
+            None
+        };
+    }
+}
+
+impl Outcome for Quote {
+    fn invalid(&self) -> Option<&'static str> {
+        return if self.trip_time == -1 {
+            Some("No cab can take so many passengers")
+        } else {
+            None
+        };
+    }
+}
+
+impl Outcome for Stop {
+    fn error(&self) -> Option<&'static str> {
+        return if self.id == -1 {
+            Some("Stop refused or not found")
+        } else {
+            None
+        };
+    }
+}
+
+// always an answer, possibly empty
+impl<T> Outcome for Vec<T> {}
+impl Outcome for usize {}
+impl Outcome for Leg {}
+impl Outcome for OrderHistory {}
+impl Outcome for Report {}
+impl Outcome for RouteWithOrders {}
+impl Outcome for ShiftReport {}
+impl Outcome for Stats {}
+impl Outcome for StopTraffic {}
+
+pub fn respond<T: Serialize + Outcome>(obj: T) -> HttpResponse {
+    if let Some(err) = obj.invalid() {
+        return HttpResponse::BadRequest().json(err);
+    }
+    if is_v2() {
+        if let Some(err) = obj.error() {
+            let status = match V2.try_with(|m| *m == Method::GET) {
+                Ok(true) => StatusCode::NOT_FOUND,
+                _ => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+            return HttpResponse::build(status).json(err);
+        }
+    }
+    return HttpResponse::Ok().json(obj);
+}